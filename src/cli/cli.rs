@@ -0,0 +1,307 @@
+use crate::config::config::{get_configuration, Encryption};
+use crate::io::cancel::{cancel_running_backup, watch_cancellation};
+use crate::io::catalog::list_snapshots;
+use crate::io::crypto::{load_cipher, Cipher, CryptoError, KEY_FILE_NAME};
+use crate::io::diff::{diff_snapshots, diff_source};
+use crate::io::io::{decrypt_snapshot, get_device_path, resume_copy};
+use crate::io::progress::Progress;
+use crate::io::restore::{restore_snapshot, ConflictPolicy};
+use crate::io::search::search_snapshots;
+use std::env;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// Options of the commands followed by a value.
+const VALUE_OPTIONS: [&str; 3] = ["--key-file", "--target", "--conflict"];
+
+/// Function to run the command given on the command line.
+/// It returns false if the arguments don't start with a known command, so the application starts as usual.
+pub fn run_command(args: &[String]) -> bool {
+    match args.first().map(|command| command.as_str()) {
+        Some("decrypt") => decrypt_command(&args[1..]),
+        Some("resume") => resume_command(),
+        Some("cancel") => cancel_command(),
+        Some("restore") => restore_command(&args[1..]),
+        Some("list") => list_command(&args[1..]),
+        Some("diff") => diff_command(&args[1..]),
+        Some("search") => search_command(&args[1..]),
+        _ => return false,
+    }
+
+    true
+}
+
+/// Function to get the value following an option, like the path in `--key-file <path>`.
+fn get_option(args: &[String], option: &str) -> Option<String> {
+    args.iter()
+        .position(|arg| arg == option)
+        .and_then(|i| args.get(i + 1))
+        .cloned()
+}
+
+/// Function to get the arguments that are neither options nor their values, in order.
+fn get_positionals(args: &[String]) -> Vec<String> {
+    let mut positionals = Vec::new();
+    let mut i = 0;
+
+    while i < args.len() {
+        if VALUE_OPTIONS.contains(&args[i].as_str()) {
+            i += 2;
+        } else if args[i].starts_with("--") {
+            i += 1;
+        } else {
+            positionals.push(args[i].clone());
+            i += 1;
+        }
+    }
+
+    positionals
+}
+
+/// Function to get the encryption key source from the command line.
+/// The `--key-file <path>` option selects a key file, otherwise the passphrase is read from the
+/// EB_RS_PASSPHRASE environment variable or from the standard input.
+fn read_encryption(args: &[String]) -> Encryption {
+    if let Some(key_file) = get_option(args, "--key-file") {
+        return Encryption::KeyFile(key_file);
+    }
+
+    if let Ok(passphrase) = env::var("EB_RS_PASSPHRASE") {
+        return Encryption::Passphrase(passphrase);
+    }
+
+    let mut passphrase = String::new();
+
+    eprint!("Passphrase: ");
+    let _ = std::io::stderr().flush();
+    let _ = std::io::stdin().read_line(&mut passphrase);
+
+    Encryption::Passphrase(passphrase.trim_end_matches(['\r', '\n']).to_string())
+}
+
+/// Function to get the cipher of a device whose backups are encrypted, the key source is read from the command line.
+/// It returns None if the device has no key record, its backups are not encrypted.
+fn read_device_cipher(device_path: &Path, args: &[String]) -> Result<Option<Cipher>, CryptoError> {
+    if !device_path.join(KEY_FILE_NAME).exists() {
+        return Ok(None);
+    }
+
+    load_cipher(device_path, &read_encryption(args), false).map(Some)
+}
+
+/// Command to write a decrypted copy of a snapshot in a target folder.
+/// Usage: `eb-rs decrypt <device> <snapshot> <target> [--key-file <path>]`
+fn decrypt_command(args: &[String]) {
+    if args.len() < 3 {
+        eprintln!("Usage: eb-rs decrypt <device> <snapshot> <target> [--key-file <path>]");
+        return;
+    }
+
+    let device_path = get_device_path(&args[0]);
+    let target = PathBuf::from(&args[2]);
+    let cipher = match load_cipher(&device_path, &read_encryption(&args[3..]), false) {
+        Ok(cipher) => cipher,
+        Err(err) => {
+            eprintln!("Error: {:?}", err);
+            return;
+        }
+    };
+
+    match decrypt_snapshot(&device_path, &args[1], &target, &cipher) {
+        Ok(_) => println!("Snapshot {} decrypted in {:?}", args[1], target),
+        // A corrupted file stops the decryption, scripts can tell it from the exit status.
+        Err(err) => {
+            eprintln!("Error: {}", err);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Command to resume the last interrupted backup on the configured device.
+/// Usage: `eb-rs resume`
+fn resume_command() {
+    let configuration = match get_configuration() {
+        Ok(configuration) => configuration,
+        Err(err) => {
+            eprintln!("Error: {:?}", err);
+            return;
+        }
+    };
+
+    let progress = Progress::new();
+    let updates = progress.subscribe();
+    let printer = std::thread::spawn(move || {
+        for update in updates {
+            eprint!("\r\x1b[K{}", update);
+        }
+
+        eprintln!();
+    });
+    let cancellation = watch_cancellation(&progress.get_cancellation_token());
+    let result = resume_copy(&configuration, &progress);
+
+    drop(cancellation);
+    drop(progress);
+    let _ = printer.join();
+
+    match result {
+        Ok(report) => println!("{}", report),
+        Err(err) => eprintln!("Error: {}", err),
+    }
+}
+
+/// Command to cancel the backup running in another process, the file being written is completed or rolled back
+/// and the snapshot is left partial, so the next backup resumes it.
+/// Usage: `eb-rs cancel`
+fn cancel_command() {
+    if cancel_running_backup() {
+        println!("The running backup has been asked to stop");
+    } else {
+        eprintln!("No backup is running");
+    }
+}
+
+/// Function to parse the conflict policy given with `--conflict`, the existing files are kept by default.
+fn parse_conflict_policy(value: Option<String>) -> Option<ConflictPolicy> {
+    match value.as_deref() {
+        None | Some("skip") => Some(ConflictPolicy::Skip),
+        Some("overwrite") => Some(ConflictPolicy::Overwrite),
+        Some("keep-both") => Some(ConflictPolicy::KeepBoth),
+        Some("newer-wins") => Some(ConflictPolicy::NewerWins),
+        Some(_) => None,
+    }
+}
+
+/// Command to restore a snapshot, all of it or the given files and folders, to their original paths or inside a target folder.
+/// The paths can be the original ones or the ones inside the snapshot, the passphrase is asked only for encrypted devices.
+/// Usage: `eb-rs restore <device> <snapshot> [<path>...] [--target <folder>] [--conflict skip|overwrite|keep-both|newer-wins] [--key-file <path>]`
+fn restore_command(args: &[String]) {
+    let positionals = get_positionals(args);
+    let conflict_policy = parse_conflict_policy(get_option(args, "--conflict"));
+
+    if positionals.len() < 2 || conflict_policy.is_none() {
+        eprintln!("Usage: eb-rs restore <device> <snapshot> [<path>...] [--target <folder>] [--conflict skip|overwrite|keep-both|newer-wins] [--key-file <path>]");
+        return;
+    }
+
+    let device_path = get_device_path(&positionals[0]);
+    let target = get_option(args, "--target").map(PathBuf::from);
+    let cipher = match read_device_cipher(&device_path, args) {
+        Ok(cipher) => cipher,
+        Err(err) => {
+            eprintln!("Error: {:?}", err);
+            return;
+        }
+    };
+
+    match restore_snapshot(
+        &device_path,
+        &positionals[1],
+        &positionals[2..],
+        target.as_deref().map(Path::new),
+        conflict_policy.unwrap(),
+        cipher.as_ref(),
+    ) {
+        Ok(report) if report.is_successful() => println!("{}", report),
+        // Scripts restoring files can tell a damaged restore from the exit status.
+        Ok(report) => {
+            println!("{}", report);
+            std::process::exit(1);
+        }
+        Err(err) => {
+            eprintln!("Error: {}", err);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Command to list the snapshots of a device, from the newest, with their state, format, size and verification.
+/// Usage: `eb-rs list <device>`
+fn list_command(args: &[String]) {
+    if args.is_empty() {
+        eprintln!("Usage: eb-rs list <device>");
+        return;
+    }
+
+    let device_path = get_device_path(&args[0]);
+
+    if !device_path.exists() {
+        eprintln!("Error: The device is not connected");
+        return;
+    }
+
+    for snapshot in list_snapshots(&device_path) {
+        println!("{}", snapshot);
+    }
+}
+
+/// Command to show what changed between two snapshots of a device, or between a snapshot and the configured folders.
+/// With `--json` the changes are written as json, for other tools.
+/// Usage: `eb-rs diff <device> <snapshot> [<snapshot>] [--json] [--key-file <path>]`
+fn diff_command(args: &[String]) {
+    let positionals = get_positionals(args);
+
+    if positionals.len() < 2 {
+        eprintln!(
+            "Usage: eb-rs diff <device> <snapshot> [<snapshot>] [--json] [--key-file <path>]"
+        );
+        return;
+    }
+
+    let device_path = get_device_path(&positionals[0]);
+    let cipher = match read_device_cipher(&device_path, args) {
+        Ok(cipher) => cipher,
+        Err(err) => {
+            eprintln!("Error: {:?}", err);
+            return;
+        }
+    };
+    let result = match positionals.get(2) {
+        Some(to_name) => diff_snapshots(&device_path, &positionals[1], to_name, cipher.as_ref()),
+        None => match get_configuration() {
+            Ok(configuration) => diff_source(
+                &configuration,
+                &device_path,
+                &positionals[1],
+                cipher.as_ref(),
+            ),
+            Err(err) => {
+                eprintln!("Error: {:?}", err);
+                return;
+            }
+        },
+    };
+
+    match result {
+        Ok(report) if args.iter().any(|arg| arg == "--json") => println!("{}", report.to_json()),
+        Ok(report) => println!("{}", report),
+        Err(err) => eprintln!("Error: {}", err),
+    }
+}
+
+/// Command to find the versions of the files matching a glob or a substring across the snapshots of a device.
+/// Every version is listed with its snapshot, so it can be restored with the `restore` command.
+/// Usage: `eb-rs search <device> <pattern> [--json] [--key-file <path>]`
+fn search_command(args: &[String]) {
+    let positionals = get_positionals(args);
+
+    if positionals.len() < 2 {
+        eprintln!("Usage: eb-rs search <device> <pattern> [--json] [--key-file <path>]");
+        return;
+    }
+
+    let device_path = get_device_path(&positionals[0]);
+    let cipher = match read_device_cipher(&device_path, args) {
+        Ok(cipher) => cipher,
+        Err(err) => {
+            eprintln!("Error: {:?}", err);
+            return;
+        }
+    };
+
+    match search_snapshots(&device_path, &positionals[1], cipher.as_ref()) {
+        Ok(report) if args.iter().any(|arg| arg == "--json") => println!("{}", report.to_json()),
+        Ok(report) => println!("{}", report),
+        Err(err) => eprintln!("Error: {}", err),
+    }
+}
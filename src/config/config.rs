@@ -1,31 +1,254 @@
 use serde::{Deserialize, Serialize};
 use serde_json;
 use std::{
+    collections::BTreeMap,
     env,
     io::{Read, Write},
     path::PathBuf,
+    process::Command,
 };
 
+/// Service of the Keychain item holding the passphrase of the backups.
+const KEYCHAIN_SERVICE: &str = "eb-rs";
+/// Account of the Keychain item holding the passphrase of the backups.
+const KEYCHAIN_ACCOUNT: &str = "backup-passphrase";
+
 /// Enum to handle various configuration errors
 #[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone)]
 pub enum ConfigError {
     ConfigCreationError,
     ConfigNotFound,
     ConfigCorrupted,
+    KeychainError,
+}
+
+/// Enum to handle the formats a snapshot can be written in.
+/// * Directory: a plain copy of the directory tree, unchanged files are hard links to the previous snapshot.
+/// * Repository: files are split in chunks stored once by hash, the snapshot only holds an index.
+/// * Archive: a single tar archive compressed with zstd.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Default, Deserialize, Serialize)]
+pub enum BackupFormat {
+    #[default]
+    Directory,
+    Repository,
+    Archive,
+}
+
+impl std::fmt::Display for BackupFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BackupFormat::Directory => write!(f, "Directory tree"),
+            BackupFormat::Repository => write!(f, "Deduplicated repository"),
+            BackupFormat::Archive => write!(f, "Compressed archive (tar.zst)"),
+        }
+    }
+}
+
+/// Enum to handle the sets of rebuildable files that can be left out of the backups.
+/// * DeveloperCaches: dependencies, virtual environments and build folders of the developer tools.
+/// * OsCaches: caches, trash and metadata files of the operating system.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Deserialize, Serialize)]
+pub enum ExcludePreset {
+    DeveloperCaches,
+    OsCaches,
+}
+
+impl std::fmt::Display for ExcludePreset {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ExcludePreset::DeveloperCaches => {
+                write!(
+                    f,
+                    "Developer caches (target, node_modules, .venv, __pycache__)"
+                )
+            }
+            ExcludePreset::OsCaches => write!(f, "OS caches (Library/Caches, trash, .DS_Store)"),
+        }
+    }
+}
+
+/// Enum to handle the sources of the key used to encrypt the backups.
+/// * Passphrase: the key is derived from the passphrase with Argon2id and a random salt stored on the device.
+///   The passphrase is written in plain text in the configuration file.
+/// * Keychain: the key is derived the same way from the passphrase stored in the macOS Keychain.
+/// * KeyFile: the key is derived from the content of the file at the given path.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Deserialize, Serialize)]
+pub enum Encryption {
+    Passphrase(String),
+    Keychain,
+    KeyFile(String),
+}
+
+/// Structure describing which snapshots are kept on the device, contains four fields.
+/// * keep_last: the number of most recent snapshots kept.
+/// * keep_daily: the number of days for which the most recent snapshot of the day is kept.
+/// * keep_weekly: the number of weeks for which the most recent snapshot of the week is kept.
+/// * keep_monthly: the number of months for which the most recent snapshot of the month is kept.
+///
+/// A snapshot is kept if any rule keeps it, with every field set to zero the snapshots are never removed.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Retention {
+    keep_last: usize,
+    keep_daily: usize,
+    keep_weekly: usize,
+    keep_monthly: usize,
+}
+
+impl Retention {
+    pub fn get_keep_last(&self) -> usize {
+        self.keep_last
+    }
+
+    pub fn get_keep_daily(&self) -> usize {
+        self.keep_daily
+    }
+
+    pub fn get_keep_weekly(&self) -> usize {
+        self.keep_weekly
+    }
+
+    pub fn get_keep_monthly(&self) -> usize {
+        self.keep_monthly
+    }
+
+    /// Function to check if the policy allows removing snapshots.
+    pub fn is_enabled(&self) -> bool {
+        self.keep_last > 0 || self.keep_daily > 0 || self.keep_weekly > 0 || self.keep_monthly > 0
+    }
+}
+
+/// Structure describing a configured path, contains three fields.
+/// * path: the absolute path of the folder or the file.
+/// * include: gitignore-style patterns of the files to back up, if empty every file is backed up.
+/// * exclude: gitignore-style patterns of the files and folders not to back up, `!` patterns include them again.
+///
+/// In the configuration file a path without patterns is written as a plain string.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Deserialize, Serialize)]
+#[serde(from = "SourcePathEntry", into = "SourcePathEntry")]
+pub struct SourcePath {
+    path: String,
+    include: Vec<String>,
+    exclude: Vec<String>,
+}
+
+impl SourcePath {
+    pub fn new(path: String) -> Self {
+        SourcePath {
+            path,
+            include: Vec::new(),
+            exclude: Vec::new(),
+        }
+    }
+
+    pub fn get_path(&self) -> String {
+        self.path.clone()
+    }
+
+    pub fn get_include(&self) -> Vec<String> {
+        self.include.clone()
+    }
+
+    pub fn get_exclude(&self) -> Vec<String> {
+        self.exclude.clone()
+    }
+}
+
+/// Enum to handle the ways a configured path can be written in the configuration file.
+#[derive(Deserialize, Serialize)]
+#[serde(untagged)]
+enum SourcePathEntry {
+    Plain(String),
+    Filtered {
+        path: String,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        include: Vec<String>,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        exclude: Vec<String>,
+    },
+}
+
+impl From<SourcePathEntry> for SourcePath {
+    fn from(entry: SourcePathEntry) -> Self {
+        match entry {
+            SourcePathEntry::Plain(path) => SourcePath::new(path),
+            SourcePathEntry::Filtered {
+                path,
+                include,
+                exclude,
+            } => SourcePath {
+                path,
+                include,
+                exclude,
+            },
+        }
+    }
+}
+
+impl From<SourcePath> for SourcePathEntry {
+    fn from(source_path: SourcePath) -> Self {
+        if source_path.include.is_empty() && source_path.exclude.is_empty() {
+            SourcePathEntry::Plain(source_path.path)
+        } else {
+            SourcePathEntry::Filtered {
+                path: source_path.path,
+                include: source_path.include,
+                exclude: source_path.exclude,
+            }
+        }
+    }
 }
 
 ///
 #[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Deserialize, Serialize)]
 pub struct Config {
     device_name: String,
-    path_names: Vec<String>,
+    #[serde(default)]
+    extra_device_names: Vec<String>,
+    path_names: Vec<SourcePath>,
+    #[serde(default)]
+    backup_format: BackupFormat,
+    #[serde(default = "default_compression_level")]
+    compression_level: i32,
+    #[serde(default)]
+    encryption: Option<Encryption>,
+    #[serde(default)]
+    retention: Retention,
+    #[serde(default = "default_parallel_copies")]
+    parallel_copies: usize,
+    #[serde(default)]
+    folder_priorities: BTreeMap<String, u32>,
+    #[serde(default)]
+    time_budget: Option<u64>,
+    #[serde(default)]
+    exclude_presets: Vec<ExcludePreset>,
+    #[serde(default)]
+    exclude_cache_dirs: bool,
+}
+
+fn default_compression_level() -> i32 {
+    3
+}
+
+fn default_parallel_copies() -> usize {
+    4
 }
 
 impl Config {
-    fn new(device_name: String, path_names: Vec<String>) -> Self {
+    pub fn new(device_name: String, path_names: Vec<String>) -> Self {
         Config {
             device_name,
-            path_names,
+            extra_device_names: Vec::new(),
+            path_names: path_names.into_iter().map(SourcePath::new).collect(),
+            backup_format: BackupFormat::default(),
+            compression_level: default_compression_level(),
+            encryption: None,
+            retention: Retention::default(),
+            parallel_copies: default_parallel_copies(),
+            folder_priorities: BTreeMap::new(),
+            time_budget: None,
+            exclude_presets: Vec::new(),
+            exclude_cache_dirs: false,
         }
     }
 
@@ -33,17 +256,123 @@ impl Config {
         self.device_name.clone()
     }
 
-    pub fn get_path_names(&self) -> Vec<String> {
+    pub fn get_extra_device_names(&self) -> Vec<String> {
+        self.extra_device_names.clone()
+    }
+
+    /// Function to get the devices every backup is written to, the main device first, without duplicates.
+    pub fn get_device_names(&self) -> Vec<String> {
+        let mut device_names = vec![self.device_name.clone()];
+
+        for device_name in self.extra_device_names.iter() {
+            if !device_names.contains(device_name) {
+                device_names.push(device_name.clone());
+            }
+        }
+
+        device_names
+    }
+
+    pub fn get_source_paths(&self) -> Vec<SourcePath> {
         self.path_names.clone()
     }
+
+    pub fn get_backup_format(&self) -> BackupFormat {
+        self.backup_format
+    }
+
+    pub fn get_compression_level(&self) -> i32 {
+        self.compression_level
+    }
+
+    pub fn get_encryption(&self) -> Option<Encryption> {
+        self.encryption.clone()
+    }
+
+    pub fn get_retention(&self) -> Retention {
+        self.retention
+    }
+
+    pub fn get_parallel_copies(&self) -> usize {
+        self.parallel_copies
+    }
+
+    pub fn get_folder_priorities(&self) -> BTreeMap<String, u32> {
+        self.folder_priorities.clone()
+    }
+
+    /// Function to get the time a backup is allowed to take, in seconds, without a budget the backup copies every file.
+    pub fn get_time_budget(&self) -> Option<u64> {
+        self.time_budget
+    }
+
+    pub fn get_exclude_presets(&self) -> Vec<ExcludePreset> {
+        self.exclude_presets.clone()
+    }
+
+    /// Function to check if the folders tagged as caches with a `CACHEDIR.TAG` file are left out of the backups.
+    pub fn get_exclude_cache_dirs(&self) -> bool {
+        self.exclude_cache_dirs
+    }
+
+    pub fn set_device_name(&mut self, device_name: String) {
+        self.device_name = device_name;
+    }
+
+    pub fn set_extra_device_names(&mut self, extra_device_names: Vec<String>) {
+        self.extra_device_names = extra_device_names;
+    }
+
+    /// Function to set the configured paths, the paths already configured keep their patterns.
+    pub fn set_path_names(&mut self, path_names: Vec<String>) {
+        self.path_names = path_names
+            .into_iter()
+            .map(|path_name| {
+                self.path_names
+                    .iter()
+                    .find(|source_path| source_path.path == path_name)
+                    .cloned()
+                    .unwrap_or(SourcePath::new(path_name))
+            })
+            .collect();
+    }
+
+    pub fn set_backup_format(&mut self, backup_format: BackupFormat) {
+        self.backup_format = backup_format;
+    }
+
+    pub fn set_compression_level(&mut self, compression_level: i32) {
+        self.compression_level = compression_level;
+    }
+
+    pub fn set_parallel_copies(&mut self, parallel_copies: usize) {
+        self.parallel_copies = parallel_copies;
+    }
+
+    pub fn set_folder_priorities(&mut self, folder_priorities: BTreeMap<String, u32>) {
+        self.folder_priorities = folder_priorities;
+    }
+
+    pub fn set_time_budget(&mut self, time_budget: Option<u64>) {
+        self.time_budget = time_budget;
+    }
+
+    pub fn set_exclude_presets(&mut self, exclude_presets: Vec<ExcludePreset>) {
+        self.exclude_presets = exclude_presets;
+    }
+
+    pub fn set_exclude_cache_dirs(&mut self, exclude_cache_dirs: bool) {
+        self.exclude_cache_dirs = exclude_cache_dirs;
+    }
+
+    pub fn set_encryption(&mut self, encryption: Option<Encryption>) {
+        self.encryption = encryption;
+    }
 }
 
-/// Function to create the configuration file and directory structure, it compiles the file with paths and device informations.
+/// Function to create the configuration file and directory structure, it compiles the file with the given configuration.
 /// The configuration file uses a json format.
-pub fn create_configuration(
-    device_name: String,
-    path_names: Vec<String>,
-) -> Result<(), ConfigError> {
+pub fn create_configuration(config: &Config) -> Result<(), ConfigError> {
     let home_dir = env::var("HOME").expect("Unable to load the home directory");
     let path_dir = PathBuf::from(home_dir.clone())
         .join("Library")
@@ -51,7 +380,7 @@ pub fn create_configuration(
         .join("eb-rs");
     let path_config = path_dir.join("config.json");
 
-    if let Err(_) = std::fs::create_dir(path_dir) {
+    if let Err(_) = std::fs::create_dir_all(path_dir) {
         return Err(ConfigError::ConfigCreationError);
     }
 
@@ -61,11 +390,9 @@ pub fn create_configuration(
         return Err(ConfigError::ConfigCreationError);
     }
 
-    let config = Config::new(device_name, path_names);
-
     if config_file
         .unwrap()
-        .write_all(serde_json::to_string_pretty(&config).unwrap().as_bytes())
+        .write_all(serde_json::to_string_pretty(config).unwrap().as_bytes())
         .is_err()
     {
         return Err(ConfigError::ConfigCreationError);
@@ -103,3 +430,49 @@ pub fn get_configuration() -> Result<Config, ConfigError> {
 
     Ok(config.unwrap())
 }
+
+/// Function to read the passphrase of the backups from the macOS Keychain, with the security tool.
+/// It returns None if the Keychain has no passphrase for eb-rs or it can't be read.
+pub fn read_keychain_passphrase() -> Option<String> {
+    let output = Command::new("security")
+        .args([
+            "find-generic-password",
+            "-s",
+            KEYCHAIN_SERVICE,
+            "-a",
+            KEYCHAIN_ACCOUNT,
+            "-w",
+        ])
+        .output()
+        .ok()?;
+
+    if !output.status.success() {
+        return None;
+    }
+
+    String::from_utf8(output.stdout)
+        .ok()
+        .map(|passphrase| passphrase.trim_end_matches('\n').to_string())
+}
+
+/// Function to store the passphrase of the backups in the macOS Keychain, with the security tool.
+/// The passphrase already stored, if any, is replaced.
+pub fn store_keychain_passphrase(passphrase: &str) -> Result<(), ConfigError> {
+    let status = Command::new("security")
+        .args([
+            "add-generic-password",
+            "-U",
+            "-s",
+            KEYCHAIN_SERVICE,
+            "-a",
+            KEYCHAIN_ACCOUNT,
+            "-w",
+            passphrase,
+        ])
+        .status();
+
+    match status {
+        Ok(status) if status.success() => Ok(()),
+        _ => Err(ConfigError::KeychainError),
+    }
+}
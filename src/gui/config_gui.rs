@@ -1,21 +1,47 @@
-use crate::config::config::{create_configuration, Config};
+use crate::config::config::{
+    create_configuration, read_keychain_passphrase, store_keychain_passphrase, BackupFormat,
+    Config, Encryption, ExcludePreset, SourcePath,
+};
 use crate::gui::gui::ExitStatus;
 use crate::io::io::*;
 use byte_unit::Byte;
 use eframe::egui;
 use eframe::egui::IconData;
-use fs_extra::dir::get_size;
+use std::collections::BTreeMap;
 use std::time::Duration;
 use std::{cell::RefCell, rc::Rc};
 
-/// App structure for egui's window implementation, contains three fields.
+/// Time budget proposed when it's enabled, in seconds.
+const DEFAULT_TIME_BUDGET: u64 = 90;
+
+/// App structure for egui's window implementation, contains twelve fields.
 /// * exit_status: determine how the window has been closed.
+/// * config: the configuration being edited, it keeps the settings that are not shown in the gui, the exclusions of the
+///   presets and of the folders tagged as caches, which change the size of the folders as soon as they are picked, and
+///   the other devices the backups are written to.
 /// * picked_paths: the array of picked paths associated with a bool to check if the path has been removed from the list.
 /// * picked_device: the device picked from the list.
+/// * picked_format: the format the snapshots are written in.
+/// * picked_compression_level: the zstd compression level used by the archive format.
+/// * picked_parallel_copies: the number of files copied at the same time, unused by the archive format.
+/// * picked_priorities: the priority of the folders, it also keeps the ones of subfolders written by hand in the configuration.
+/// * picked_time_budget: the time a backup is allowed to take, in seconds, if any.
+/// * picked_encryption: the source of the key the backups are encrypted with, if any.
+/// * picked_passphrase: the passphrase typed to encrypt the backups, empty to keep the one already configured.
+/// * encryption_error: the reason the picked encryption can't be saved, if any.
 struct App {
     exit_status: Rc<RefCell<ExitStatus>>,
+    config: Config,
     picked_paths: Vec<(Folder, bool)>,
     picked_device: Option<Device>,
+    picked_format: BackupFormat,
+    picked_compression_level: i32,
+    picked_parallel_copies: usize,
+    picked_priorities: BTreeMap<String, u32>,
+    picked_time_budget: Option<u64>,
+    picked_encryption: Option<Encryption>,
+    picked_passphrase: String,
+    encryption_error: Option<String>,
 }
 
 impl App {
@@ -25,17 +51,27 @@ impl App {
         exit_status: Rc<RefCell<ExitStatus>>,
         old_config: Option<Config>,
     ) -> Self {
+        let config = old_config
+            .clone()
+            .unwrap_or(Config::new(String::new(), Vec::new()));
         let (picked_paths, picked_device) = if let Some(old_config) = old_config {
             let device_name = old_config.get_device_name();
-            let path_names = old_config.get_path_names();
+            let source_paths = old_config.get_source_paths();
             let attached_devices = get_ext_devices();
 
             (
-                path_names
+                source_paths
                     .iter()
-                    .map(|path_name| {
-                        if let Ok(folder_size) = get_size(path_name) {
-                            (Folder::new(path_name.clone(), folder_size), false)
+                    .map(|source_path| {
+                        // The size counts only the files backed up, with the patterns of the path.
+                        if std::path::Path::new(&source_path.get_path()).exists() {
+                            (
+                                Folder::new(
+                                    source_path.get_path(),
+                                    source_size(&config, source_path),
+                                ),
+                                false,
+                            )
                         } else {
                             (Folder::new(String::new(), 0), true)
                         }
@@ -58,13 +94,74 @@ impl App {
             exit_status,
             picked_paths,
             picked_device,
+            picked_format: config.get_backup_format(),
+            picked_compression_level: config.get_compression_level(),
+            picked_parallel_copies: config.get_parallel_copies(),
+            picked_priorities: config.get_folder_priorities(),
+            picked_time_budget: config.get_time_budget(),
+            picked_encryption: config.get_encryption(),
+            picked_passphrase: String::new(),
+            encryption_error: None,
+            config,
         }
     }
 
+    /// Function to get the size of the files of a folder that are backed up, with its patterns and the exclude presets.
+    fn folder_size(&self, path_name: &str) -> u64 {
+        let source_path = self
+            .config
+            .get_source_paths()
+            .into_iter()
+            .find(|source_path| source_path.get_path() == path_name)
+            .unwrap_or(SourcePath::new(path_name.to_string()));
+
+        source_size(&self.config, &source_path)
+    }
+
+    /// Function to compute again the size of the picked folders, once the exclusions have changed.
+    fn update_sizes(&mut self) {
+        let sizes = self
+            .picked_paths
+            .iter()
+            .map(|path| self.folder_size(&path.0.get_path()))
+            .collect::<Vec<u64>>();
+
+        for (path, size) in self.picked_paths.iter_mut().zip(sizes) {
+            path.0 = Folder::new(path.0.get_path(), size);
+        }
+    }
+
+    /// Function to get the encryption to save in the configuration.
+    /// A passphrase typed, or one still written in the configuration file, is moved to the Keychain, so the
+    /// configuration file never holds it. A key file is kept as it is.
+    fn save_encryption(&self) -> Result<Option<Encryption>, String> {
+        let passphrase = match self.picked_encryption.as_ref() {
+            None => return Ok(None),
+            Some(Encryption::KeyFile(key_file)) => {
+                return Ok(Some(Encryption::KeyFile(key_file.clone())))
+            }
+            _ if !self.picked_passphrase.is_empty() => self.picked_passphrase.clone(),
+            Some(Encryption::Passphrase(passphrase)) => passphrase.clone(),
+            Some(Encryption::Keychain) if read_keychain_passphrase().is_some() => {
+                return Ok(Some(Encryption::Keychain))
+            }
+            Some(Encryption::Keychain) => {
+                return Err("Type the passphrase the backups are encrypted with.".to_string())
+            }
+        };
+
+        store_keychain_passphrase(&passphrase)
+            .map(|_| Some(Encryption::Keychain))
+            .map_err(|_| "Unable to store the passphrase in the Keychain.".to_string())
+    }
+
     /// Function to render the gui, to be called inside the update function of the eframe::App trait.
     /// It renders two main components.
-    /// * a file picker to choose a path, the list of choosen paths and a button to remove them.
-    /// * a drop-down menu to choose between the external devices.
+    /// * a file picker to choose a path, the list of choosen paths with their priority and a button to remove them.
+    /// * a drop-down menu to choose between the external devices, checkboxes to write the backups to other devices too and
+    ///   a drop-down menu to choose the backup format, with its compression level.
+    /// * a checkbox to limit the time a backup is allowed to take, one to encrypt the backups with the field of the
+    ///   passphrase, the ones of the exclude presets and the one of the folders tagged as caches.
     fn show_config_gui(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
         egui::CentralPanel::default().show(ctx, |ui| {
             ui.heading("Choose up to five directories to save in case of emergency!");
@@ -84,7 +181,7 @@ impl App {
             if self.picked_paths.len() < 5 && ui.button("Open directory…").clicked() {
                 if let Some(path) = rfd::FileDialog::new().pick_folder() {
                     let path_name = path.to_str().unwrap().to_string();
-                    let path_size = get_size(path).unwrap();
+                    let path_size = self.folder_size(&path_name);
 
                     if !self
                         .picked_paths
@@ -103,6 +200,15 @@ impl App {
                     }
 
                     ui.label(format!("{}", self.picked_paths[i].0));
+                    ui.add(
+                        egui::DragValue::new(
+                            self.picked_priorities
+                                .entry(self.picked_paths[i].0.get_path())
+                                .or_insert(0),
+                        )
+                        .range(0..=9)
+                        .prefix("Priority: "),
+                    );
                 });
             }
 
@@ -127,38 +233,208 @@ impl App {
                 ui.horizontal(|ui| {
                     ui.label(format!("{}", picked_device));
                 });
+
+                // The backup copies the most valuable files that fit, so a small device is only a warning.
+                if picked_device.get_size()
+                    <= self
+                        .picked_paths
+                        .iter()
+                        .map(|path| path.0.get_size())
+                        .sum::<u64>()
+                {
+                    ui.label("The directories don't fit on the device, only the most valuable files will be copied.");
+                }
+            }
+
+            // The other devices get the same backup, the configured ones are listed even when they are not attached.
+            let picked_name = self
+                .picked_device
+                .as_ref()
+                .map(|device| device.get_name())
+                .unwrap_or_default();
+            let mut device_names = self.config.get_extra_device_names();
+
+            for device in get_ext_devices() {
+                if !device_names.contains(&device.get_name()) {
+                    device_names.push(device.get_name());
+                }
+            }
+
+            for device_name in device_names.into_iter().filter(|name| *name != picked_name) {
+                let mut extra_device_names = self.config.get_extra_device_names();
+                let mut enabled = extra_device_names.contains(&device_name);
+
+                if ui
+                    .checkbox(&mut enabled, format!("Also back up to {}", device_name))
+                    .changed()
+                {
+                    extra_device_names.retain(|extra_name| *extra_name != device_name);
+
+                    if enabled {
+                        extra_device_names.push(device_name);
+                    }
+
+                    self.config.set_extra_device_names(extra_device_names);
+                }
+            }
+
+            ui.add_space(20.0);
+
+            egui::ComboBox::new("format-menu", "Backup format")
+                .selected_text(format!("{}", self.picked_format))
+                .show_ui(ui, |ui| {
+                    for backup_format in [
+                        BackupFormat::Directory,
+                        BackupFormat::Repository,
+                        BackupFormat::Archive,
+                    ] {
+                        ui.selectable_value(
+                            &mut self.picked_format,
+                            backup_format,
+                            format!("{}", backup_format),
+                        );
+                    }
+                });
+
+            if self.picked_format == BackupFormat::Archive {
+                // The whole range of zstd, so a level set in the configuration file is kept as it is.
+                ui.add(
+                    egui::Slider::new(
+                        &mut self.picked_compression_level,
+                        zstd::compression_level_range(),
+                    )
+                    .text("Compression level"),
+                );
+            } else {
+                ui.add(
+                    egui::Slider::new(&mut self.picked_parallel_copies, 1..=16)
+                        .text("Parallel copies"),
+                );
+            }
+
+            ui.horizontal(|ui| {
+                let mut enabled = self.picked_time_budget.is_some();
+
+                if ui.checkbox(&mut enabled, "Time budget").changed() {
+                    self.picked_time_budget = enabled.then_some(DEFAULT_TIME_BUDGET);
+                }
+
+                if let Some(time_budget) = self.picked_time_budget.as_mut() {
+                    ui.add(
+                        egui::DragValue::new(time_budget)
+                            .range(10..=3600)
+                            .suffix(" seconds"),
+                    );
+                }
+            });
+
+            let mut encrypted = self.picked_encryption.is_some();
+
+            if ui.checkbox(&mut encrypted, "Encrypt the backups").changed() {
+                self.picked_encryption = encrypted
+                    .then(|| self.config.get_encryption().unwrap_or(Encryption::Keychain));
+            }
+
+            match self.picked_encryption.as_ref() {
+                Some(Encryption::KeyFile(key_file)) => {
+                    ui.label(format!("Key file: {}", key_file));
+                }
+                Some(_) => {
+                    // An empty field keeps the passphrase already configured.
+                    let hint = match self.config.get_encryption() {
+                        Some(Encryption::Passphrase(_)) | Some(Encryption::Keychain) => "Unchanged",
+                        _ => "",
+                    };
+
+                    ui.horizontal(|ui| {
+                        ui.label("Passphrase, stored in the Keychain:");
+                        ui.add(
+                            egui::TextEdit::singleline(&mut self.picked_passphrase)
+                                .password(true)
+                                .hint_text(hint),
+                        );
+                    });
+                }
+                None => {}
+            }
+
+            if let Some(encryption_error) = &self.encryption_error {
+                ui.label(encryption_error);
+            }
+
+            for preset in [ExcludePreset::DeveloperCaches, ExcludePreset::OsCaches] {
+                let mut presets = self.config.get_exclude_presets();
+                let mut enabled = presets.contains(&preset);
+
+                if ui
+                    .checkbox(&mut enabled, format!("Leave out {}", preset))
+                    .changed()
+                {
+                    presets.retain(|picked_preset| *picked_preset != preset);
+
+                    if enabled {
+                        presets.push(preset);
+                    }
+
+                    self.config.set_exclude_presets(presets);
+                    self.update_sizes();
+                }
+            }
+
+            let mut exclude_cache_dirs = self.config.get_exclude_cache_dirs();
+
+            if ui
+                .checkbox(
+                    &mut exclude_cache_dirs,
+                    "Leave out the folders tagged as caches (CACHEDIR.TAG)",
+                )
+                .changed()
+            {
+                self.config.set_exclude_cache_dirs(exclude_cache_dirs);
+                self.update_sizes();
             }
         });
 
         egui::TopBottomPanel::bottom("bottom-panel")
             .show_separator_line(false)
             .show(ctx, |ui| {
-                if ui.button("Start emergency backup!").clicked() {
-                    let total_size = self
-                        .picked_paths
-                        .iter()
-                        .map(|path| path.0.get_size())
-                        .sum::<u64>();
+                if ui.button("Start emergency backup!").clicked()
+                    && !self.picked_paths.is_empty()
+                    && self.picked_device.is_some()
+                {
+                    let encryption = match self.save_encryption() {
+                        Ok(encryption) => encryption,
+                        Err(encryption_error) => {
+                            self.encryption_error = Some(encryption_error);
+
+                            return;
+                        }
+                    };
 
-                    if self.picked_paths.len() > 0
-                        && self
-                            .picked_device
+                    *self.exit_status.borrow_mut() = ExitStatus::COMPLETED;
+
+                    self.config
+                        .set_device_name(self.picked_device.take().unwrap().get_name());
+                    self.config.set_path_names(
+                        self.picked_paths
                             .clone()
-                            .is_some_and(|val| val.get_size() > total_size)
-                    {
-                        *self.exit_status.borrow_mut() = ExitStatus::COMPLETED;
-
-                        let _ = create_configuration(
-                            self.picked_device.take().unwrap().get_name(),
-                            self.picked_paths
-                                .clone()
-                                .into_iter()
-                                .map(|path| path.0.get_path())
-                                .collect(),
-                        );
+                            .into_iter()
+                            .map(|path| path.0.get_path())
+                            .collect(),
+                    );
+                    self.config.set_backup_format(self.picked_format);
+                    self.config
+                        .set_compression_level(self.picked_compression_level);
+                    self.config.set_parallel_copies(self.picked_parallel_copies);
+                    self.picked_priorities.retain(|_, priority| *priority > 0);
+                    self.config
+                        .set_folder_priorities(self.picked_priorities.clone());
+                    self.config.set_time_budget(self.picked_time_budget);
+                    self.config.set_encryption(encryption);
 
-                        ctx.send_viewport_cmd(egui::ViewportCommand::Close);
-                    }
+                    let _ = create_configuration(&self.config);
+
+                    ctx.send_viewport_cmd(egui::ViewportCommand::Close);
                 }
             });
 
@@ -181,7 +457,7 @@ pub fn start_config_gui(old_config: Option<Config>) -> ExitStatus {
         viewport: egui::ViewportBuilder::default()
             .with_active(true)
             .with_resizable(false)
-            .with_inner_size([640.0, 360.0])
+            .with_inner_size([640.0, 620.0])
             .with_maximize_button(false)
             .with_drag_and_drop(false)
             .with_icon(IconData::default()),
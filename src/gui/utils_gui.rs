@@ -1,10 +1,14 @@
 use std::{
     cell::RefCell,
     rc::Rc,
+    sync::mpsc::Receiver,
     time::{Duration, Instant},
 };
 
 use crate::gui::gui::ExitStatus;
+use crate::io::progress::{Progress, ProgressPhase, ProgressUpdate};
+use crate::io::report::{BackupError, BackupReport, DevicesReport};
+use byte_unit::Byte;
 use eframe::egui;
 use eframe::egui::IconData;
 
@@ -13,16 +17,22 @@ const WARNING_WINDOW_DURATION: u64 = 15;
 
 ///
 enum UtilsGuiType {
-    WarningGui,
+    Warning,
+    Progress(Progress),
+    Summary(Result<DevicesReport, BackupError>),
 }
 
-/// App structure for egui's window implementation, contains three fields.
+/// App structure for egui's window implementation, contains five fields.
 /// * exit_status: determine how the window has been closed.
 /// * time_left: the **Instant** object created at the creation time of the window.
+/// * updates: the progress updates of the backup, only for the progress gui.
+/// * last_update: the last progress update received.
 struct App {
     gui_type: UtilsGuiType,
     exit_status: Rc<RefCell<ExitStatus>>,
     time_left: Instant,
+    updates: Option<Receiver<ProgressUpdate>>,
+    last_update: ProgressUpdate,
 }
 
 impl App {
@@ -32,10 +42,17 @@ impl App {
         exit_status: Rc<RefCell<ExitStatus>>,
         gui_type: UtilsGuiType,
     ) -> Self {
+        let updates = match &gui_type {
+            UtilsGuiType::Progress(progress) => Some(progress.subscribe()),
+            UtilsGuiType::Warning | UtilsGuiType::Summary(_) => None,
+        };
+
         App {
             gui_type,
             exit_status,
             time_left: Instant::now(),
+            updates,
+            last_update: ProgressUpdate::default(),
         }
     }
 
@@ -66,13 +83,225 @@ impl App {
 
         ctx.request_repaint_after(Duration::from_millis(200));
     }
+
+    /// Function to render the gui, to be called inside the update function of the eframe::App trait.
+    /// It renders the file being copied, a progress bar and the time left, the gui closes itself once the backup is finished.
+    /// The cancel button asks the copy engine to stop, the snapshot is left partial and it's resumed by the next backup.
+    fn show_progress_gui(
+        &mut self,
+        ctx: &egui::Context,
+        _frame: &mut eframe::Frame,
+        progress: &Progress,
+    ) {
+        if let Some(updates) = self.updates.as_ref() {
+            while let Ok(update) = updates.try_recv() {
+                self.last_update = update;
+            }
+        }
+
+        let update = &self.last_update;
+
+        if update.get_phase() == ProgressPhase::Finished {
+            *self.exit_status.borrow_mut() = if progress.is_cancelled() {
+                ExitStatus::UNCOMPLETED
+            } else {
+                ExitStatus::COMPLETED
+            };
+            ctx.send_viewport_cmd(egui::ViewportCommand::Close);
+        }
+
+        egui::CentralPanel::default().show(ctx, |ui| {
+            ui.heading("Backup in progress");
+
+            match update.get_phase() {
+                ProgressPhase::Scanning => {
+                    ui.label("Scanning the folders to back up...");
+                }
+                ProgressPhase::Copying | ProgressPhase::Finished => {
+                    ui.label(format!("Copying: {}", update.get_current_file().display()));
+                }
+                ProgressPhase::Verifying => {
+                    ui.label("Reading back the copied files from the device...");
+                }
+            }
+
+            let fraction = if update.get_bytes_total() > 0 {
+                update.get_bytes_done() as f32 / update.get_bytes_total() as f32
+            } else {
+                0.0
+            };
+
+            ui.add(egui::ProgressBar::new(fraction).show_percentage());
+            ui.label(format!(
+                "{} of {} files, {:.1} of {:.1}",
+                update.get_files_done(),
+                update.get_files_total(),
+                Byte::from(update.get_bytes_done())
+                    .get_appropriate_unit(byte_unit::UnitType::Decimal),
+                Byte::from(update.get_bytes_total())
+                    .get_appropriate_unit(byte_unit::UnitType::Decimal)
+            ));
+
+            if let Some(eta) = update.get_eta() {
+                ui.label(format!("About {} seconds left.", eta.as_secs()));
+            }
+
+            if progress.is_cancelled() {
+                ui.label("Cancelling the backup...");
+            }
+        });
+
+        egui::TopBottomPanel::bottom("bottom-panel")
+            .show_separator_line(false)
+            .show(ctx, |ui| {
+                if ui
+                    .add_enabled(!progress.is_cancelled(), egui::Button::new("Cancel"))
+                    .clicked()
+                {
+                    progress.cancel();
+                }
+            });
+
+        ctx.request_repaint_after(Duration::from_millis(100));
+    }
+
+    /// Function to render the outcome of the backup on a device: the snapshot written with its size and duration, the files
+    /// that couldn't be copied and the result of the verification, or the reason the backup failed.
+    /// A button opens the snapshot in the Finder.
+    fn show_device_summary(
+        &self,
+        ui: &mut egui::Ui,
+        device_name: &str,
+        result: &Result<BackupReport, BackupError>,
+    ) {
+        match result {
+            Ok(report) => {
+                let has_errors = !report.get_failures().is_empty()
+                    || (!report.is_out_of_time() && !report.get_verification().is_passed());
+
+                ui.heading(format!(
+                    "{}: {}",
+                    device_name,
+                    // Failed files and a failed verification are listed below, the heading must not hide them.
+                    if report.is_cancelled() {
+                        "backup cancelled!"
+                    } else if has_errors && report.is_partial() {
+                        "backup partially completed with errors!"
+                    } else if has_errors {
+                        "backup completed with errors!"
+                    } else if report.is_partial() {
+                        "backup partially completed!"
+                    } else if report.is_successful() {
+                        "backup completed!"
+                    } else {
+                        "backup completed with errors!"
+                    }
+                ));
+
+                if report.is_cancelled() {
+                    ui.label("The backup is incomplete, the next one will resume it.");
+                } else if report.is_partial() {
+                    ui.label(format!(
+                        "{} files have been left out, the copied ones are consistent.",
+                        report.get_left_out().len()
+                    ));
+                }
+
+                ui.horizontal(|ui| {
+                    ui.label(format!(
+                        "Snapshot: {}",
+                        report.get_snapshot_path().display()
+                    ));
+
+                    if ui.button("Open snapshot").clicked() {
+                        let _ = std::process::Command::new("open")
+                            .arg(report.get_snapshot_path())
+                            .spawn();
+                    }
+                });
+                ui.label(format!(
+                    "{} files, {:.1} in total, {:.1} copied in {:.1} seconds.",
+                    report.get_file_count(),
+                    Byte::from(report.get_byte_count())
+                        .get_appropriate_unit(byte_unit::UnitType::Decimal),
+                    Byte::from(report.get_copied_bytes())
+                        .get_appropriate_unit(byte_unit::UnitType::Decimal),
+                    report.get_duration().as_secs_f64()
+                ));
+
+                ui.add_space(2.0);
+
+                for failure in report.get_failures() {
+                    ui.label(format!("Failed {}", failure));
+                }
+
+                for skipped in report.get_skipped() {
+                    ui.label(format!("Skipped {}", skipped));
+                }
+
+                for left_out in report.get_left_out() {
+                    ui.label(format!("Left out {}", left_out));
+                }
+
+                if report.is_out_of_time() {
+                    ui.label("Verification skipped, the time budget has run out.");
+                } else if !report.is_cancelled() {
+                    ui.label(report.get_verification().to_string());
+                }
+            }
+            Err(err) => {
+                ui.heading(format!("{}: backup failed!", device_name));
+                ui.label(err.to_string());
+            }
+        }
+    }
+
+    /// Function to render the gui, to be called inside the update function of the eframe::App trait.
+    /// It renders the outcome of the backup on every configured device, or the reason the backup failed on all of them.
+    /// The gui stays open until it's closed.
+    fn show_summary_gui(
+        &self,
+        ctx: &egui::Context,
+        _frame: &mut eframe::Frame,
+        result: &Result<DevicesReport, BackupError>,
+    ) {
+        egui::CentralPanel::default().show(ctx, |ui| match result {
+            Ok(report) => {
+                egui::ScrollArea::vertical().show(ui, |ui| {
+                    for (device_name, result) in report.get_results() {
+                        self.show_device_summary(ui, &device_name, &result);
+                        ui.add_space(10.0);
+                    }
+                });
+            }
+            Err(err) => {
+                ui.heading("Backup failed!");
+                ui.label(err.to_string());
+            }
+        });
+
+        egui::TopBottomPanel::bottom("bottom-panel")
+            .show_separator_line(false)
+            .show(ctx, |ui| {
+                if ui.button("Close").clicked() {
+                    *self.exit_status.borrow_mut() = ExitStatus::COMPLETED;
+                    ctx.send_viewport_cmd(egui::ViewportCommand::Close);
+                }
+            });
+    }
 }
 
 impl eframe::App for App {
     fn update(&mut self, ctx: &egui::Context, frame: &mut eframe::Frame) {
         if *self.exit_status.borrow() == ExitStatus::PROCESSING {
-            match self.gui_type {
-                UtilsGuiType::WarningGui => self.show_warning_gui(ctx, frame),
+            match &self.gui_type {
+                UtilsGuiType::Warning => self.show_warning_gui(ctx, frame),
+                UtilsGuiType::Progress(progress) => {
+                    let progress = progress.clone();
+
+                    self.show_progress_gui(ctx, frame, &progress)
+                }
+                UtilsGuiType::Summary(result) => self.show_summary_gui(ctx, frame, result),
             }
         }
     }
@@ -81,15 +310,32 @@ impl eframe::App for App {
 /// Function to start the warning gui, the caller waits until the window is closed.
 /// It returns the exit status.
 pub fn start_warning_gui() -> ExitStatus {
-    start_utils_gui(UtilsGuiType::WarningGui)
+    start_utils_gui(UtilsGuiType::Warning)
+}
+
+/// Function to start the progress gui of a backup running in another thread, the caller waits until the backup is finished.
+/// It returns COMPLETED if the backup has finished, UNCOMPLETED if it has been cancelled.
+pub fn start_progress_gui(progress: &Progress) -> ExitStatus {
+    start_utils_gui(UtilsGuiType::Progress(progress.clone()))
+}
+
+/// Function to start the summary gui of a finished backup, the caller waits until the window is closed.
+/// It returns the exit status.
+pub fn start_summary_gui(result: &Result<DevicesReport, BackupError>) -> ExitStatus {
+    start_utils_gui(UtilsGuiType::Summary(result.clone()))
 }
 
 fn start_utils_gui(gui_type: UtilsGuiType) -> ExitStatus {
+    // The summary lists the failed files, so it needs more room.
+    let height = match gui_type {
+        UtilsGuiType::Summary(_) => 400.0,
+        UtilsGuiType::Warning | UtilsGuiType::Progress(_) => 240.0,
+    };
     let options = eframe::NativeOptions {
         viewport: egui::ViewportBuilder::default()
             .with_active(true)
             .with_resizable(false)
-            .with_inner_size([640.0, 240.0])
+            .with_inner_size([640.0, height])
             .with_drag_and_drop(false)
             .with_close_button(false)
             .with_maximize_button(false)
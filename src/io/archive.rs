@@ -0,0 +1,752 @@
+use crate::io::crypto::Cipher;
+use crate::io::fanout::Sources;
+use crate::io::io::{hash_reader, SourceFile};
+use crate::io::journal::{read_journal, Journal, JOURNAL_FILE_NAME};
+use crate::io::manifest::{SnapshotEntry, SnapshotManifest};
+use crate::io::progress::Progress;
+use crate::io::report::BackupReport;
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use zstd::stream::{Decoder, Encoder};
+
+/// Name of the archive written inside the snapshot folder.
+pub const ARCHIVE_FILE_NAME: &str = "archive.tar.zst";
+/// Name of the archive written inside the snapshot folder when the encryption is configured.
+pub const ENCRYPTED_ARCHIVE_FILE_NAME: &str = "archive.tar.zst.enc";
+
+/// Size of the source files after which the archive continues in a new volume.
+const VOLUME_SIZE: u64 = 1024 * 1024 * 1024;
+
+/// Extensions of the file formats that are already compressed, recompressing them only wastes time.
+const COMPRESSED_EXTENSIONS: [&str; 40] = [
+    "7z", "aac", "apk", "avi", "br", "bz2", "cab", "dmg", "docx", "epub", "flac", "gif", "gz",
+    "heic", "jar", "jpeg", "jpg", "lz", "lz4", "lzma", "m4a", "m4v", "mkv", "mov", "mp3", "mp4",
+    "odt", "ogg", "png", "pptx", "rar", "tgz", "txz", "webm", "webp", "woff2", "xlsx", "xz", "zip",
+    "zst",
+];
+
+/// Function to check if a file is already compressed, looking at its extension.
+pub fn is_compressed(path: &Path) -> bool {
+    path.extension()
+        .map(|extension| extension.to_string_lossy().to_lowercase())
+        .is_some_and(|extension| COMPRESSED_EXTENSIONS.contains(&extension.as_str()))
+}
+
+/// Size of the raw blocks of a stored frame, the biggest block a zstd frame can hold.
+const RAW_BLOCK_SIZE: usize = 128 * 1024;
+
+/// Writer storing the data in a zstd frame made of raw blocks, without compressing it, contains two fields.
+/// * inner: the writer of the frame.
+/// * block: the data of the block being filled.
+struct RawFrame<W: Write> {
+    inner: W,
+    block: Vec<u8>,
+}
+
+impl<W: Write> RawFrame<W> {
+    /// Function to start a frame, its header has no content size and no checksum, with a window as big as a block.
+    fn new(mut inner: W) -> std::io::Result<Self> {
+        // The magic number, an empty frame header descriptor and a window of 2^17 bytes.
+        inner.write_all(&0xFD2FB528u32.to_le_bytes())?;
+        inner.write_all(&[0x00, 0x38])?;
+
+        Ok(RawFrame {
+            inner,
+            block: Vec::with_capacity(RAW_BLOCK_SIZE),
+        })
+    }
+
+    /// Function to write the block being filled, the header holds the last block flag, the raw type and the size.
+    fn write_block(&mut self, last: bool) -> std::io::Result<()> {
+        let header = last as u32 | (self.block.len() as u32) << 3;
+
+        self.inner.write_all(&header.to_le_bytes()[..3])?;
+        self.inner.write_all(&self.block)?;
+        self.block.clear();
+
+        Ok(())
+    }
+
+    /// Function to end the frame with its last block, it returns the inner writer.
+    fn finish(mut self) -> std::io::Result<W> {
+        self.write_block(true)?;
+
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for RawFrame<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        // A full block waits for more data, so the last block of a frame is empty only if the whole frame is.
+        if self.block.len() == RAW_BLOCK_SIZE {
+            self.write_block(false)?;
+        }
+
+        let len = buf.len().min(RAW_BLOCK_SIZE - self.block.len());
+
+        self.block.extend_from_slice(&buf[..len]);
+
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Enum describing the zstd frame being written.
+/// * Compressed: the data is compressed by the encoder.
+/// * Stored: the data is stored in raw blocks, for the files that are already compressed.
+enum Frame<W: Write> {
+    Compressed(Encoder<'static, W>),
+    Stored(RawFrame<W>),
+}
+
+impl<W: Write> Frame<W> {
+    /// Function to start a frame compressed at the given level, or stored if there is no level.
+    fn new(inner: W, level: Option<i32>) -> std::io::Result<Self> {
+        match level {
+            Some(level) => Ok(Frame::Compressed(Encoder::new(inner, level)?)),
+            None => Ok(Frame::Stored(RawFrame::new(inner)?)),
+        }
+    }
+
+    /// Function to end the frame, it returns the inner writer.
+    fn finish(self) -> std::io::Result<W> {
+        match self {
+            Frame::Compressed(encoder) => encoder.finish(),
+            Frame::Stored(raw_frame) => raw_frame.finish(),
+        }
+    }
+}
+
+impl<W: Write> Write for Frame<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Frame::Compressed(encoder) => encoder.write(buf),
+            Frame::Stored(raw_frame) => raw_frame.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Frame::Compressed(encoder) => encoder.flush(),
+            Frame::Stored(raw_frame) => raw_frame.flush(),
+        }
+    }
+}
+
+/// Writer compressing the data in zstd frames, contains two fields.
+/// * frame: the current frame.
+/// * level: the compression level of the current frame, None if its data is stored without compression.
+///
+/// Changing the level ends the current frame and starts a new one, a sequence of frames is still a valid zstd stream.
+/// If ending a frame fails the writer is left without a frame, and every following write is an error.
+struct FrameWriter<W: Write> {
+    frame: Option<Frame<W>>,
+    level: Option<i32>,
+}
+
+impl<W: Write> FrameWriter<W> {
+    fn new(inner: W, level: Option<i32>) -> std::io::Result<Self> {
+        Ok(FrameWriter {
+            frame: Some(Frame::new(inner, level)?),
+            level,
+        })
+    }
+
+    /// Function to get the error returned once the writer has no frame.
+    fn closed_error() -> std::io::Error {
+        std::io::Error::new(
+            std::io::ErrorKind::BrokenPipe,
+            "The archive stream has been closed after an error",
+        )
+    }
+
+    /// Function to set the compression level of the data written next, None to store it without compression.
+    fn set_level(&mut self, level: Option<i32>) -> std::io::Result<()> {
+        if level != self.level {
+            let inner = self.frame.take().ok_or_else(Self::closed_error)?.finish()?;
+
+            self.frame = Some(Frame::new(inner, level)?);
+            self.level = level;
+        }
+
+        Ok(())
+    }
+
+    /// Function to end the last frame, it returns the inner writer.
+    fn finish(mut self) -> std::io::Result<W> {
+        self.frame.take().ok_or_else(Self::closed_error)?.finish()
+    }
+}
+
+impl<W: Write> Write for FrameWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self.frame.as_mut() {
+            Some(frame) => frame.write(buf),
+            None => Err(Self::closed_error()),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self.frame.as_mut() {
+            Some(frame) => frame.flush(),
+            None => Err(Self::closed_error()),
+        }
+    }
+}
+
+/// Reader computing the BLAKE3 hash of the data read through it.
+struct HashReader<R: Read> {
+    inner: R,
+    hasher: blake3::Hasher,
+}
+
+impl<R: Read> Read for HashReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.inner.read(buf)?;
+
+        self.hasher.update(&buf[..read]);
+
+        Ok(read)
+    }
+}
+
+/// Reader giving exactly the size declared in the header of a tar entry, contains four fields.
+/// * inner: the reader of the source file.
+/// * remaining: the bytes still to give.
+/// * short: if the source has ended before the declared size.
+/// * error: the error returned by the source, if any.
+///
+/// Once the source ends or fails the rest of the entry is filled with zeros, so the following entries stay aligned.
+struct SizedReader<R: Read> {
+    inner: R,
+    remaining: u64,
+    short: bool,
+    error: Option<std::io::Error>,
+}
+
+impl<R: Read> Read for SizedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let len = buf.len().min(self.remaining as usize);
+
+        if len == 0 {
+            return Ok(0);
+        }
+
+        let read = if self.short || self.error.is_some() {
+            0
+        } else {
+            match self.inner.read(&mut buf[..len]) {
+                Ok(read) => read,
+                Err(err) if err.kind() == std::io::ErrorKind::Interrupted => return Err(err),
+                Err(err) => {
+                    self.error = Some(err);
+                    0
+                }
+            }
+        };
+        let read = if read == 0 {
+            self.short = self.error.is_none();
+            buf[..len].fill(0);
+
+            len
+        } else {
+            read
+        };
+
+        self.remaining -= read as u64;
+
+        Ok(read)
+    }
+}
+
+/// Function to append a file to the archive, the header is built from the metadata of the source.
+/// It returns the BLAKE3 hash of the archived content, the bytes read are added to the progress.
+/// A file growing while it's archived is cut at the size found by the scan. A file shrinking, or failing to be read,
+/// is an error: its entry is filled with zeros up to the size in the header, so the following entries can still be read.
+fn append_file<W: Write>(
+    builder: &mut tar::Builder<FrameWriter<W>>,
+    file: &SourceFile,
+    sources: &Sources,
+    progress: &Progress,
+) -> std::io::Result<String> {
+    let metadata = std::fs::metadata(file.get_source())?;
+    let source = sources.open(&file.get_source())?;
+    let mut header = tar::Header::new_gnu();
+
+    header.set_metadata(&metadata);
+    header.set_size(file.get_size());
+    header.set_cksum();
+
+    let mut reader = HashReader {
+        inner: SizedReader {
+            inner: progress.reader(source),
+            remaining: file.get_size(),
+            short: false,
+            error: None,
+        },
+        hasher: blake3::Hasher::new(),
+    };
+
+    builder.append_data(&mut header, file.get_relative_path(), &mut reader)?;
+
+    if let Some(err) = reader.inner.error {
+        return Err(err);
+    }
+
+    if reader.inner.short {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::UnexpectedEof,
+            "the file has shrunk while it was archived",
+        ));
+    }
+
+    Ok(reader.hasher.finalize().to_hex().to_string())
+}
+
+/// Function to write the source files as a tar stream compressed with zstd, it returns the inner writer.
+/// Every archived file is added to the entries, the files that can't be read are added to the failures of the report.
+fn write_tar<W: Write>(
+    writer: W,
+    files: &[SourceFile],
+    compression_level: i32,
+    entries: &mut Vec<SnapshotEntry>,
+    report: &mut BackupReport,
+    sources: &Sources,
+    progress: &Progress,
+) -> std::io::Result<W> {
+    let mut builder = tar::Builder::new(FrameWriter::new(writer, Some(compression_level))?);
+
+    for file in files {
+        if progress.should_stop() {
+            break;
+        }
+
+        let level = if is_compressed(&file.get_source()) {
+            None
+        } else {
+            Some(compression_level)
+        };
+
+        builder.get_mut().set_level(level)?;
+
+        progress.start_file(&file.get_source());
+
+        match append_file(&mut builder, file, sources, progress) {
+            Ok(hash) => {
+                report.add_copied_bytes(file.get_size());
+                entries.push(SnapshotEntry::from_source_file(file, hash));
+            }
+            Err(err) => report.add_failure(&file.get_source(), err.to_string()),
+        }
+
+        progress.finish_file();
+    }
+
+    builder.into_inner()?.finish()
+}
+
+/// Function to get the path of a volume of the archive, the first volume has the name of the whole archive.
+fn volume_path(snapshot_path: &Path, volume: usize, cipher: Option<&Cipher>) -> PathBuf {
+    let name = if cipher.is_some() {
+        ENCRYPTED_ARCHIVE_FILE_NAME
+    } else {
+        ARCHIVE_FILE_NAME
+    };
+
+    if volume == 0 {
+        snapshot_path.join(name)
+    } else {
+        snapshot_path.join(name.replacen("archive", &format!("archive-{}", volume), 1))
+    }
+}
+
+/// Function to write the source files in a volume of the archive, it returns the files stored in it.
+fn write_volume(
+    path: &Path,
+    files: &[SourceFile],
+    compression_level: i32,
+    cipher: Option<&Cipher>,
+    report: &mut BackupReport,
+    sources: &Sources,
+    progress: &Progress,
+) -> std::io::Result<Vec<SnapshotEntry>> {
+    let mut entries = Vec::new();
+    let archive_file = BufWriter::new(File::create(path)?);
+    let archive_file = match cipher {
+        Some(cipher) => {
+            let encrypt_writer = cipher.encrypt_writer(archive_file)?;
+
+            write_tar(
+                encrypt_writer,
+                files,
+                compression_level,
+                &mut entries,
+                report,
+                sources,
+                progress,
+            )?
+            .finish()?
+        }
+        None => write_tar(
+            archive_file,
+            files,
+            compression_level,
+            &mut entries,
+            report,
+            sources,
+            progress,
+        )?,
+    };
+
+    archive_file.into_inner()?.sync_all()?;
+
+    Ok(entries)
+}
+
+/// Function to write the source files in a tar archive compressed with zstd, inside the snapshot folder.
+/// The archive is written sequentially, already compressed files are stored in raw blocks instead of being recompressed.
+/// With a cipher the whole archive is encrypted, so file names are hidden too. It returns the files stored in the archive.
+///
+/// Big backups are split in volumes of about 1 GiB, each one a complete archive recorded in the journal once written.
+/// A resumed backup keeps the volumes in the journal and writes the remaining files in the following ones.
+#[allow(clippy::too_many_arguments)]
+pub fn write_archive(
+    snapshot_path: &Path,
+    files: &[SourceFile],
+    compression_level: i32,
+    cipher: Option<&Cipher>,
+    journal: &mut Journal,
+    report: &mut BackupReport,
+    sources: &Sources,
+    progress: &Progress,
+) -> std::io::Result<Vec<SnapshotEntry>> {
+    let volumes = read_journal::<Vec<SnapshotEntry>>(snapshot_path, cipher).unwrap_or_default();
+    let mut volume = volumes.len();
+    let mut entries = volumes
+        .into_iter()
+        .flatten()
+        .collect::<Vec<SnapshotEntry>>();
+    let archived = entries
+        .iter()
+        .map(|entry| entry.get_source())
+        .collect::<HashSet<String>>();
+    let (done, remaining): (Vec<SourceFile>, Vec<SourceFile>) = files
+        .iter()
+        .cloned()
+        .partition(|file| archived.contains(file.get_source().to_string_lossy().as_ref()));
+    let mut start = 0;
+
+    for file in done {
+        progress.skip_file(file.get_size());
+    }
+
+    // The first volume is always written, so an empty backup still has an archive.
+    while (start < remaining.len() || volume == 0) && !progress.should_stop() {
+        let mut end = start;
+        let mut volume_size = 0;
+
+        while end < remaining.len() && (end == start || volume_size < VOLUME_SIZE) {
+            volume_size += remaining[end].get_size();
+            end += 1;
+        }
+
+        let volume_entries = write_volume(
+            &volume_path(snapshot_path, volume, cipher),
+            &remaining[start..end],
+            compression_level,
+            cipher,
+            report,
+            sources,
+            progress,
+        )?;
+
+        journal.append(&volume_entries)?;
+        entries.extend(volume_entries);
+        start = end;
+        volume += 1;
+    }
+
+    Ok(entries)
+}
+
+/// Function to open a volume of the archive of a snapshot for reading, the volume is decrypted if a cipher is given.
+fn open_volume(
+    path: &Path,
+    cipher: Option<&Cipher>,
+) -> std::io::Result<tar::Archive<Box<dyn Read>>> {
+    let archive_file = BufReader::new(File::open(path)?);
+    let reader: Box<dyn Read> = match cipher {
+        Some(cipher) => Box::new(Decoder::new(cipher.decrypt_reader(archive_file)?)?),
+        None => Box::new(Decoder::with_buffer(archive_file)?),
+    };
+
+    Ok(tar::Archive::new(reader))
+}
+
+/// Function to get the paths of the volumes of the archive of a snapshot, in order.
+fn volume_paths(snapshot_path: &Path, cipher: Option<&Cipher>) -> Vec<PathBuf> {
+    (0..)
+        .map(|volume| volume_path(snapshot_path, volume, cipher))
+        .take_while(|path| path.exists())
+        .collect()
+}
+
+/// Function to get the paths of the files completely archived in a snapshot, from its manifest, or from the journal of
+/// the volumes written so far if the snapshot is incomplete. A file that has failed while it was archived has its entry
+/// filled with zeros, and it's not among them. It returns None for a legacy snapshot, which has neither of them.
+fn archived_paths(snapshot_path: &Path, cipher: Option<&Cipher>) -> Option<HashSet<String>> {
+    let entries = match SnapshotManifest::load(snapshot_path, cipher) {
+        Some(manifest) => manifest.get_entries(),
+        None if snapshot_path.join(JOURNAL_FILE_NAME).exists() => {
+            read_journal::<Vec<SnapshotEntry>>(snapshot_path, cipher)
+                .unwrap_or_default()
+                .into_iter()
+                .flatten()
+                .collect()
+        }
+        None => return None,
+    };
+
+    Some(entries.iter().map(|entry| entry.get_path()).collect())
+}
+
+/// Function to unpack the archive of a snapshot inside the target folder, the archive is decrypted if a cipher is given.
+/// The entries of the files that have not been archived completely are skipped, once the other files are unpacked they
+/// are reported in the error returned.
+pub fn extract_archive(
+    snapshot_path: &Path,
+    target: &Path,
+    cipher: Option<&Cipher>,
+) -> std::io::Result<()> {
+    let archived = archived_paths(snapshot_path, cipher);
+    let mut skipped = Vec::new();
+
+    std::fs::create_dir_all(target)?;
+
+    for path in volume_paths(snapshot_path, cipher) {
+        let mut archive = open_volume(&path, cipher)?;
+
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+            let path = entry.path()?.to_string_lossy().to_string();
+
+            if archived
+                .as_ref()
+                .is_some_and(|archived| !archived.contains(&path))
+            {
+                skipped.push(path);
+                continue;
+            }
+
+            entry.unpack_in(target)?;
+        }
+    }
+
+    if !skipped.is_empty() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("Files not archived completely: {}", skipped.join(", ")),
+        ));
+    }
+
+    Ok(())
+}
+
+/// Function to read back the whole archive of a snapshot, it fills the map with the hash of every file found in it.
+/// The volumes are read as streams, an error stops the reading and the following files are not hashed.
+pub fn hash_archive(
+    snapshot_path: &Path,
+    cipher: Option<&Cipher>,
+    hashes: &mut HashMap<String, std::io::Result<String>>,
+) -> std::io::Result<()> {
+    for path in volume_paths(snapshot_path, cipher) {
+        let mut archive = open_volume(&path, cipher)?;
+
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+            let path = entry.path()?.to_string_lossy().to_string();
+
+            hashes.insert(path, hash_reader(&mut entry));
+        }
+    }
+
+    Ok(())
+}
+
+/// Function to read the files of the archive of a snapshot, one at a time, in the order they have been archived.
+/// The callback is called with the path of every file inside the snapshot, its modification time and a reader of its
+/// content. The volumes are read as streams, an error stops the reading and the following files are not read.
+/// A file that has not been archived completely is given with an error instead of its content.
+pub fn read_archive<F: FnMut(&str, u64, std::io::Result<&mut dyn Read>)>(
+    snapshot_path: &Path,
+    cipher: Option<&Cipher>,
+    mut on_file: F,
+) -> std::io::Result<()> {
+    let archived = archived_paths(snapshot_path, cipher);
+
+    for path in volume_paths(snapshot_path, cipher) {
+        let mut archive = open_volume(&path, cipher)?;
+
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+
+            if !entry.header().entry_type().is_file() {
+                continue;
+            }
+
+            let path = entry.path()?.to_string_lossy().to_string();
+            // The archive keeps the modification time in seconds.
+            let modified = entry.header().mtime().unwrap_or(0) * 1_000_000_000;
+
+            if archived
+                .as_ref()
+                .is_some_and(|archived| !archived.contains(&path))
+            {
+                let err = std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    "the file has not been archived completely, its content is not valid",
+                );
+
+                on_file(&path, modified, Err(err));
+            } else {
+                on_file(&path, modified, Ok(&mut entry));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_file_shrunk_after_the_scan_keeps_the_archive_readable() {
+        let dir = std::env::temp_dir().join(format!("eb-rs-archive-{}", std::process::id()));
+        let snapshot_path = dir.join("snapshot");
+        let shrunk = dir.join("shrunk.bin");
+        let next = dir.join("next.txt");
+
+        std::fs::create_dir_all(&snapshot_path).unwrap();
+        std::fs::write(&shrunk, vec![7u8; 10_000]).unwrap();
+        std::fs::write(&next, b"still readable").unwrap();
+
+        let files = [&shrunk, &next].map(|path| {
+            SourceFile::new(
+                path.to_path_buf(),
+                PathBuf::from(path.file_name().unwrap()),
+                std::fs::metadata(path).unwrap().len(),
+                0,
+            )
+        });
+
+        // The file is truncated between the scan and the copy.
+        File::options()
+            .write(true)
+            .open(&shrunk)
+            .unwrap()
+            .set_len(100)
+            .unwrap();
+
+        let mut journal = Journal::open(&snapshot_path, None).unwrap();
+        let mut report = BackupReport::new(snapshot_path.clone(), false);
+        let entries = write_archive(
+            &snapshot_path,
+            &files,
+            3,
+            None,
+            &mut journal,
+            &mut report,
+            &Sources::direct(),
+            &Progress::new(),
+        )
+        .unwrap();
+        let mut contents = HashMap::new();
+        let read = read_archive(&snapshot_path, None, |path, _, reader| {
+            let mut content = Vec::new();
+            let read = reader.and_then(|reader| reader.read_to_end(&mut content));
+
+            contents.insert(path.to_string(), read.map(|_| content));
+        });
+        let target = dir.join("target");
+        let extracted = extract_archive(&snapshot_path, &target, None);
+        let shrunk_extracted = target.join("shrunk.bin").exists();
+        let next_extracted = std::fs::read(target.join("next.txt")).unwrap();
+
+        drop(journal);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert!(read.is_ok());
+        // The zeros filling the entry of the shrunk file are never given as its content.
+        assert!(contents["shrunk.bin"].is_err());
+        assert!(extracted.is_err());
+        assert!(!shrunk_extracted);
+        assert_eq!(next_extracted, b"still readable");
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].get_path(), "next.txt");
+        assert_eq!(report.get_failures().len(), 1);
+        assert_eq!(contents["next.txt"].as_ref().unwrap(), b"still readable");
+    }
+
+    #[test]
+    fn already_compressed_files_are_stored_without_compression() {
+        let dir = std::env::temp_dir().join(format!("eb-rs-stored-{}", std::process::id()));
+        let snapshot_path = dir.join("snapshot");
+        let photo = dir.join("photo.jpg");
+        let notes = dir.join("notes.txt");
+        // More than two raw blocks of zeros, which would shrink to almost nothing if they were compressed.
+        let photo_content = vec![0u8; 300_000];
+
+        std::fs::create_dir_all(&snapshot_path).unwrap();
+        std::fs::write(&photo, &photo_content).unwrap();
+        std::fs::write(&notes, vec![b'a'; 300_000]).unwrap();
+
+        let files = [&photo, &notes].map(|path| {
+            SourceFile::new(
+                path.to_path_buf(),
+                PathBuf::from(path.file_name().unwrap()),
+                std::fs::metadata(path).unwrap().len(),
+                0,
+            )
+        });
+        let mut journal = Journal::open(&snapshot_path, None).unwrap();
+        let mut report = BackupReport::new(snapshot_path.clone(), false);
+        let entries = write_archive(
+            &snapshot_path,
+            &files,
+            3,
+            None,
+            &mut journal,
+            &mut report,
+            &Sources::direct(),
+            &Progress::new(),
+        )
+        .unwrap();
+        let archive_size = std::fs::metadata(snapshot_path.join(ARCHIVE_FILE_NAME))
+            .unwrap()
+            .len();
+        let mut contents = HashMap::new();
+        let read = read_archive(&snapshot_path, None, |path, _, reader| {
+            let mut content = Vec::new();
+
+            reader.unwrap().read_to_end(&mut content).unwrap();
+            contents.insert(path.to_string(), content);
+        });
+
+        drop(journal);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert!(read.is_ok());
+        assert_eq!(entries.len(), 2);
+        // The text is compressed, the photo is stored as it is.
+        assert!(archive_size > 300_000 && archive_size < 400_000);
+        assert_eq!(contents["photo.jpg"], photo_content);
+        assert_eq!(contents["notes.txt"], vec![b'a'; 300_000]);
+    }
+}
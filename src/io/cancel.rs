@@ -0,0 +1,150 @@
+use signal_hook::consts::{SIGINT, SIGTERM, SIGUSR1};
+use signal_hook::SigId;
+use std::env;
+use std::io::Read;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::Instant;
+use sysinfo::{Pid, ProcessesToUpdate, Signal, System};
+
+/// Name of the file holding the process id of the running backup, inside the configuration folder.
+const PID_FILE_NAME: &str = "backup.pid";
+
+/// Token to cancel a running backup, the copy engine checks it between files and while it reads a file.
+/// It also carries the deadline of a backup with a time budget, the copy engine stops the same way once it has passed.
+/// It can be cloned and shared between threads, every clone cancels the same backup.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken {
+    cancelled: Arc<AtomicBool>,
+    deadline: Arc<OnceLock<Instant>>,
+}
+
+impl CancellationToken {
+    /// Function to cancel the backup, the file being written is completed or rolled back and the snapshot is left partial.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// Function to set the time the backup has to stop at, only the first deadline set is kept.
+    pub fn set_deadline(&self, deadline: Instant) {
+        let _ = self.deadline.set(deadline);
+    }
+
+    pub fn is_out_of_time(&self) -> bool {
+        self.deadline
+            .get()
+            .is_some_and(|deadline| Instant::now() >= *deadline)
+    }
+
+    /// Function to check if the copy engine has to stop, because the backup has been cancelled or it has run out of time.
+    pub fn should_stop(&self) -> bool {
+        self.is_cancelled() || self.is_out_of_time()
+    }
+
+    /// Function to wrap a reader, reading through it fails once the backup is cancelled or it has run out of time.
+    pub fn reader<R: Read>(&self, inner: R) -> CancellableReader<R> {
+        CancellableReader {
+            inner,
+            token: self.clone(),
+        }
+    }
+}
+
+/// Reader failing once the backup is cancelled or it has run out of time, so a big file doesn't delay the stop.
+pub struct CancellableReader<R: Read> {
+    inner: R,
+    token: CancellationToken,
+}
+
+impl<R: Read> Read for CancellableReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        // Interrupted is retried by the standard library, so a generic error is returned.
+        if self.token.is_cancelled() {
+            return Err(std::io::Error::other("The backup has been cancelled"));
+        }
+
+        if self.token.is_out_of_time() {
+            return Err(std::io::Error::other("The backup has run out of time"));
+        }
+
+        self.inner.read(buf)
+    }
+}
+
+/// Guard keeping a backup cancellable from outside the process, created by **watch_cancellation**.
+/// When it's dropped the signals no longer cancel the backup and the process id file is removed.
+/// The signals can't get back their default behaviour, they are ignored, so the process is expected to exit after the backup.
+pub struct CancellationGuard {
+    signal_ids: Vec<SigId>,
+    pid_path: Option<PathBuf>,
+}
+
+impl Drop for CancellationGuard {
+    fn drop(&mut self) {
+        for signal_id in self.signal_ids.drain(..) {
+            signal_hook::low_level::unregister(signal_id);
+        }
+
+        if let Some(pid_path) = self.pid_path.as_ref() {
+            let _ = std::fs::remove_file(pid_path);
+        }
+    }
+}
+
+/// Function to get the path of the file holding the process id of the running backup.
+fn pid_path() -> PathBuf {
+    let home_dir = env::var("HOME").expect("Unable to load the home directory");
+
+    PathBuf::from(home_dir)
+        .join("Library")
+        .join("Application Support")
+        .join("eb-rs")
+        .join(PID_FILE_NAME)
+}
+
+/// Function to make a backup cancellable from outside the process, until the returned guard is dropped.
+/// SIGINT, SIGTERM and SIGUSR1 cancel the backup instead of killing the process,
+/// the process id is written in the configuration folder so `eb-rs cancel` can find it.
+pub fn watch_cancellation(token: &CancellationToken) -> CancellationGuard {
+    let signal_ids = [SIGINT, SIGTERM, SIGUSR1]
+        .iter()
+        .filter_map(|signal| signal_hook::flag::register(*signal, token.cancelled.clone()).ok())
+        .collect();
+    let pid_path = pid_path();
+    let pid_path = std::fs::write(&pid_path, std::process::id().to_string())
+        .ok()
+        .map(|_| pid_path);
+
+    CancellationGuard {
+        signal_ids,
+        pid_path,
+    }
+}
+
+/// Function to cancel the backup running in another process, sending it SIGUSR1.
+/// It returns false if no backup is running.
+pub fn cancel_running_backup() -> bool {
+    let pid = match std::fs::read_to_string(pid_path())
+        .ok()
+        .and_then(|pid| pid.trim().parse::<usize>().ok())
+    {
+        Some(pid) => Pid::from(pid),
+        None => return false,
+    };
+    let mut system = System::new();
+
+    system.refresh_processes(ProcessesToUpdate::Some(&[pid]), true);
+
+    // A process id file left by a crash may refer to an unrelated process.
+    match system.process(pid) {
+        Some(process) if process.name() == "eb-rs" => {
+            process.kill_with(Signal::User1).unwrap_or(false)
+        }
+        _ => false,
+    }
+}
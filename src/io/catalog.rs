@@ -0,0 +1,235 @@
+use crate::config::config::BackupFormat;
+use crate::io::archive::read_archive;
+use crate::io::crypto::Cipher;
+use crate::io::io::{read_directory, snapshot_format, snapshot_totals, SNAPSHOT_NAME_FORMAT};
+use crate::io::journal::{
+    partial_format, partial_path, read_completion, read_journal, PARTIAL_EXTENSION,
+};
+use crate::io::manifest::{SnapshotEntry, SnapshotManifest};
+use crate::io::report::BackupError;
+use crate::io::repository::read_snapshot;
+use byte_unit::{Byte, UnitType};
+use chrono::NaiveDateTime;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+/// Enum describing the state of a snapshot found on the device.
+/// * Complete: every source file has been written, the snapshot has its completion record.
+/// * Partial: the snapshot has been completed leaving out some source files on purpose, the ones it holds are consistent.
+/// * Incomplete: the snapshot is still being written or has been interrupted, the next backup in its format resumes it.
+/// * Legacy: the snapshot has been written before the completion record existed, it can't be known to be complete.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum SnapshotStatus {
+    Complete,
+    Partial,
+    Incomplete,
+    Legacy,
+}
+
+impl std::fmt::Display for SnapshotStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SnapshotStatus::Complete => write!(f, "complete"),
+            SnapshotStatus::Partial => write!(f, "partial"),
+            SnapshotStatus::Incomplete => write!(f, "incomplete"),
+            SnapshotStatus::Legacy => write!(f, "legacy"),
+        }
+    }
+}
+
+/// Structure describing a snapshot found on the device, contains seven fields.
+/// * name: the final name of the snapshot folder, without the suffix of an incomplete snapshot.
+/// * time: the time the snapshot has been started at, read from its name.
+/// * status: the state of the snapshot.
+/// * backup_format: the format the snapshot is written in.
+/// * file_count: the number of files in the snapshot.
+/// * byte_count: the total size of the files in the snapshot, in bytes. Without a completion record it's the size of
+///   the snapshot folder on the device, which for the repository format doesn't count the chunks.
+/// * verified: if every file has been read back from the device identical to its source.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct SnapshotInfo {
+    name: String,
+    time: NaiveDateTime,
+    status: SnapshotStatus,
+    backup_format: BackupFormat,
+    file_count: usize,
+    byte_count: u64,
+    verified: bool,
+}
+
+impl SnapshotInfo {
+    pub fn get_name(&self) -> String {
+        self.name.clone()
+    }
+
+    pub fn get_time(&self) -> NaiveDateTime {
+        self.time
+    }
+
+    pub fn get_status(&self) -> SnapshotStatus {
+        self.status
+    }
+
+    pub fn get_backup_format(&self) -> BackupFormat {
+        self.backup_format
+    }
+
+    /// Function to check if the snapshot has its completion record, the only kind that can be trusted and pruned.
+    pub fn is_complete(&self) -> bool {
+        matches!(
+            self.status,
+            SnapshotStatus::Complete | SnapshotStatus::Partial
+        )
+    }
+
+    /// Function to get the path of the snapshot folder, an incomplete snapshot is still in its partial folder.
+    pub fn get_path(&self, device_path: &Path) -> PathBuf {
+        match self.status {
+            SnapshotStatus::Incomplete => partial_path(device_path, &self.name),
+            _ => device_path.join(&self.name),
+        }
+    }
+}
+
+impl std::fmt::Display for SnapshotInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}  {:<10}  {:<29}  {:>7} files  {:>10}{}",
+            self.name,
+            self.status.to_string(),
+            self.backup_format.to_string(),
+            self.file_count,
+            format!(
+                "{:.1}",
+                Byte::from(self.byte_count).get_appropriate_unit(UnitType::Decimal)
+            ),
+            if self.verified { "  verified" } else { "" }
+        )
+    }
+}
+
+/// Function to read the snapshot held by a folder of the device, from its name and its metadata.
+/// Folders whose name is not the one of a snapshot are ignored. A snapshot folder without any metadata has been
+/// written by an older version of eb-rs: it's adopted as a legacy snapshot and its files are counted on the device.
+fn read_snapshot_info(device_path: &Path, folder_name: &str) -> Option<SnapshotInfo> {
+    let suffix = format!(".{}", PARTIAL_EXTENSION);
+    let (name, incomplete) = match folder_name.strip_suffix(&suffix) {
+        Some(name) => (name, true),
+        None => (folder_name, false),
+    };
+    let time = NaiveDateTime::parse_from_str(name, SNAPSHOT_NAME_FORMAT).ok()?;
+    let snapshot_path = device_path.join(folder_name);
+
+    if !snapshot_path.is_dir() {
+        return None;
+    }
+
+    let completion = read_completion(&snapshot_path).filter(|_| !incomplete);
+    let (status, backup_format) = match completion.as_ref() {
+        Some(completion) if completion.is_partial() => {
+            (SnapshotStatus::Partial, snapshot_format(&snapshot_path))
+        }
+        Some(_) => (SnapshotStatus::Complete, snapshot_format(&snapshot_path)),
+        None if incomplete => (
+            SnapshotStatus::Incomplete,
+            partial_format(&snapshot_path).unwrap_or(snapshot_format(&snapshot_path)),
+        ),
+        None => (SnapshotStatus::Legacy, snapshot_format(&snapshot_path)),
+    };
+    let (file_count, byte_count) = match completion.as_ref() {
+        Some(completion) => (completion.get_file_count(), completion.get_byte_count()),
+        None => snapshot_totals(&snapshot_path),
+    };
+
+    Some(SnapshotInfo {
+        name: name.to_string(),
+        time,
+        status,
+        backup_format,
+        file_count,
+        byte_count,
+        verified: completion.is_some_and(|completion| completion.is_verified()),
+    })
+}
+
+/// Function to list the snapshots of the device, in any state, sorted from the newest.
+pub fn list_snapshots(device_path: &Path) -> Vec<SnapshotInfo> {
+    let mut snapshots = std::fs::read_dir(device_path)
+        .map(|entries| {
+            entries
+                .flatten()
+                .filter_map(|entry| {
+                    read_snapshot_info(device_path, &entry.file_name().to_string_lossy())
+                })
+                .collect::<Vec<SnapshotInfo>>()
+        })
+        .unwrap_or_default();
+
+    snapshots.sort_by_key(|snapshot| std::cmp::Reverse(snapshot.time));
+
+    snapshots
+}
+
+/// Function to find a snapshot of the device by its name, with or without the suffix of an incomplete snapshot.
+/// If both a complete and an incomplete snapshot have the name, the complete one is returned.
+pub fn find_snapshot(device_path: &Path, snapshot_name: &str) -> Option<SnapshotInfo> {
+    let suffix = format!(".{}", PARTIAL_EXTENSION);
+    let name = snapshot_name.strip_suffix(&suffix).unwrap_or(snapshot_name);
+
+    read_snapshot_info(device_path, name)
+        .or_else(|| read_snapshot_info(device_path, &format!("{}{}", name, suffix)))
+}
+
+/// Function to list the files of a snapshot with their size, modification time and hash, from its manifest.
+/// An incomplete archive lists the files in its journal. Any other snapshot without a manifest, like a legacy one, is read
+/// whole to hash its files and their sources are unknown.
+pub fn snapshot_entries(
+    device_path: &Path,
+    snapshot: &SnapshotInfo,
+    cipher: Option<&Cipher>,
+) -> Result<Vec<SnapshotEntry>, BackupError> {
+    let snapshot_path = snapshot.get_path(device_path);
+
+    if let Some(manifest) = SnapshotManifest::load(&snapshot_path, cipher) {
+        return Ok(manifest.get_entries());
+    }
+
+    // The journal of an incomplete archive lists the files of the volumes written so far, the other entries aren't valid.
+    if snapshot.status == SnapshotStatus::Incomplete
+        && snapshot.backup_format == BackupFormat::Archive
+    {
+        return read_journal::<Vec<SnapshotEntry>>(&snapshot_path, cipher)
+            .map(|volumes| volumes.into_iter().flatten().collect())
+            .map_err(|err| BackupError::SnapshotUnreadable(err.to_string()));
+    }
+
+    let mut entries = Vec::new();
+    let mut read_error = None;
+    let on_file = |path: &str, modified: u64, reader: std::io::Result<&mut dyn Read>| {
+        let mut hasher = blake3::Hasher::new();
+
+        match reader.and_then(|reader| std::io::copy(reader, &mut hasher)) {
+            Ok(size) => entries.push(SnapshotEntry::new(
+                String::new(),
+                path.to_string(),
+                size,
+                modified,
+                hasher.finalize().to_hex().to_string(),
+            )),
+            Err(err) => read_error = Some(format!("{}: {}", path, err)),
+        }
+    };
+    let read = match snapshot.get_backup_format() {
+        BackupFormat::Directory => read_directory(&snapshot_path, cipher, on_file),
+        BackupFormat::Repository => read_snapshot(device_path, &snapshot_path, cipher, on_file),
+        BackupFormat::Archive => read_archive(&snapshot_path, cipher, on_file),
+    };
+
+    // A file that can't be read would be missing from the list, as if it wasn't in the snapshot.
+    match (read, read_error) {
+        (Err(err), _) => Err(BackupError::SnapshotUnreadable(err.to_string())),
+        (_, Some(message)) => Err(BackupError::SnapshotUnreadable(message)),
+        _ => Ok(entries),
+    }
+}
@@ -0,0 +1,532 @@
+use crate::config::config::{read_keychain_passphrase, Encryption};
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use serde::{Deserialize, Serialize};
+use std::io::{Read, Write};
+use std::path::{Component, Path, PathBuf};
+
+/// Name of the file, in the root of the device, describing how the encryption key is derived.
+pub const KEY_FILE_NAME: &str = ".eb-rs-key.json";
+
+/// Size of the plaintext segments of an encrypted stream, 64 KiB.
+const SEGMENT_SIZE: usize = 64 * 1024;
+/// Size of the authentication tag appended to every encrypted segment.
+const TAG_SIZE: usize = 16;
+/// Size of the random nonce prefix written at the beginning of an encrypted stream.
+const NONCE_PREFIX_SIZE: usize = 19;
+/// Size of the synthetic IV of an encrypted name.
+const SIV_SIZE: usize = 16;
+/// Longest component of an encrypted path, the longest name most file systems accept.
+const MAX_COMPONENT_SIZE: usize = 255;
+/// Character ending the components of an encrypted name split across several ones, but the last, it's not base32.
+const CONTINUATION_MARK: char = '-';
+/// Alphabet of the lowercase base32 encoding used for encrypted names, safe on case-insensitive file systems.
+const BASE32_ALPHABET: &[u8; 32] = b"abcdefghijklmnopqrstuvwxyz234567";
+
+/// Enum to handle various encryption errors
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone)]
+pub enum CryptoError {
+    KeyFileNotFound,
+    KeyNotFound,
+    KeyCreationError,
+    WrongKey,
+    PassphraseNotFound,
+    Corrupted,
+}
+
+/// Structure stored on the device to derive the key again, contains three fields.
+/// * kdf: the key derivation function, "argon2id" for passphrases or "key-file".
+/// * salt: the hex encoded random salt used with passphrases.
+/// * check: the hex encoded keyed hash used to recognize a wrong passphrase or key file.
+#[derive(Debug, PartialEq, Eq, Clone, Deserialize, Serialize)]
+struct KeyRecord {
+    kdf: String,
+    salt: String,
+    check: String,
+}
+
+/// Structure holding the keys derived from the master key, contains three fields.
+/// * content_key: the key used to encrypt file contents and metadata.
+/// * name_key: the key used to encrypt file names.
+/// * hash_key: the key used to compute hashes that must not reveal the content, like chunk identifiers.
+#[derive(Clone)]
+pub struct Cipher {
+    content_key: [u8; 32],
+    name_key: [u8; 32],
+    hash_key: [u8; 32],
+}
+
+impl Cipher {
+    fn new(master_key: &[u8; 32]) -> Self {
+        Cipher {
+            content_key: blake3::derive_key("eb-rs content encryption", master_key),
+            name_key: blake3::derive_key("eb-rs name encryption", master_key),
+            hash_key: blake3::derive_key("eb-rs keyed hash", master_key),
+        }
+    }
+
+    /// Function to compute a hex encoded keyed hash of the data.
+    pub fn keyed_hash(&self, data: &[u8]) -> String {
+        blake3::keyed_hash(&self.hash_key, data)
+            .to_hex()
+            .to_string()
+    }
+
+    /// Function to encrypt a small piece of data with a random nonce, like a metadata file.
+    pub fn seal(&self, plaintext: &[u8]) -> Vec<u8> {
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = XChaCha20Poly1305::new(&self.content_key.into())
+            .encrypt(&nonce, plaintext)
+            .unwrap();
+
+        [nonce.as_slice(), ciphertext.as_slice()].concat()
+    }
+
+    /// Function to decrypt data encrypted with the seal function.
+    pub fn open(&self, data: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        if data.len() < 24 {
+            return Err(CryptoError::Corrupted);
+        }
+
+        XChaCha20Poly1305::new(&self.content_key.into())
+            .decrypt(XNonce::from_slice(&data[..24]), &data[24..])
+            .map_err(|_| CryptoError::Corrupted)
+    }
+
+    /// Function to encrypt a file name, the same name always gives the same encrypted name.
+    /// The result is encoded in lowercase base32, so it's a valid name on every file system, but it can be longer than
+    /// a name is allowed to be.
+    pub fn encrypt_name(&self, name: &str) -> String {
+        let name_hash = blake3::keyed_hash(&self.name_key, name.as_bytes());
+        let siv = &name_hash.as_bytes()[..SIV_SIZE];
+        let ciphertext = XChaCha20Poly1305::new(&self.name_key.into())
+            .encrypt(&name_nonce(siv), name.as_bytes())
+            .unwrap();
+
+        base32_encode(&[siv, ciphertext.as_slice()].concat())
+    }
+
+    /// Function to decrypt a file name encrypted with the encrypt_name function.
+    pub fn decrypt_name(&self, encrypted_name: &str) -> Result<String, CryptoError> {
+        let data = base32_decode(encrypted_name).ok_or(CryptoError::Corrupted)?;
+
+        if data.len() < SIV_SIZE + TAG_SIZE {
+            return Err(CryptoError::Corrupted);
+        }
+
+        let plaintext = XChaCha20Poly1305::new(&self.name_key.into())
+            .decrypt(&name_nonce(&data[..SIV_SIZE]), &data[SIV_SIZE..])
+            .map_err(|_| CryptoError::Corrupted)?;
+
+        String::from_utf8(plaintext).map_err(|_| CryptoError::Corrupted)
+    }
+
+    /// Function to encrypt every component of a relative path.
+    /// An encrypted name too long for a single component is split across several ones, every one but the last ends
+    /// with the continuation mark. A name up to 127 bytes long always fits in one component.
+    pub fn encrypt_path(&self, path: &Path) -> Result<PathBuf, CryptoError> {
+        let mut encrypted_path = PathBuf::new();
+
+        for component in path.components() {
+            let name = match component {
+                Component::Normal(name) => self.encrypt_name(&name.to_string_lossy()),
+                _ => return Err(CryptoError::Corrupted),
+            };
+
+            if name.len() <= MAX_COMPONENT_SIZE {
+                encrypted_path.push(name);
+                continue;
+            }
+
+            let parts = name
+                .as_bytes()
+                .chunks(MAX_COMPONENT_SIZE - 1)
+                .collect::<Vec<&[u8]>>();
+
+            for (i, part) in parts.iter().enumerate() {
+                // The base32 alphabet is ascii, so every part is a valid string.
+                let part = String::from_utf8_lossy(part);
+
+                if i < parts.len() - 1 {
+                    encrypted_path.push(format!("{}{}", part, CONTINUATION_MARK));
+                } else {
+                    encrypted_path.push(part.as_ref());
+                }
+            }
+        }
+
+        Ok(encrypted_path)
+    }
+
+    /// Function to decrypt every component of a relative path encrypted with the encrypt_path function.
+    /// The components of a name split across several ones are joined before it's decrypted.
+    pub fn decrypt_path(&self, path: &Path) -> Result<PathBuf, CryptoError> {
+        let mut decrypted_path = PathBuf::new();
+        let mut name = String::new();
+
+        for component in path.components() {
+            let part = match component {
+                Component::Normal(part) => part.to_string_lossy(),
+                _ => return Err(CryptoError::Corrupted),
+            };
+
+            match part.strip_suffix(CONTINUATION_MARK) {
+                Some(part) => name.push_str(part),
+                None => {
+                    name.push_str(&part);
+                    decrypted_path.push(self.decrypt_name(&name)?);
+                    name.clear();
+                }
+            }
+        }
+
+        // A path can't end in the middle of a name.
+        if !name.is_empty() {
+            return Err(CryptoError::Corrupted);
+        }
+
+        Ok(decrypted_path)
+    }
+
+    /// Function to wrap a writer in an encrypted stream, the stream must be closed with the finish function.
+    pub fn encrypt_writer<W: Write>(&self, mut inner: W) -> std::io::Result<EncryptWriter<W>> {
+        let mut nonce_prefix = [0u8; NONCE_PREFIX_SIZE];
+
+        OsRng.fill_bytes(&mut nonce_prefix);
+        inner.write_all(&nonce_prefix)?;
+
+        Ok(EncryptWriter {
+            inner,
+            aead: XChaCha20Poly1305::new(&self.content_key.into()),
+            nonce_prefix,
+            counter: 0,
+            buffer: Vec::with_capacity(SEGMENT_SIZE),
+        })
+    }
+
+    /// Function to wrap a reader of a stream encrypted with an EncryptWriter, it gives back the plaintext.
+    pub fn decrypt_reader<R: Read>(&self, mut inner: R) -> std::io::Result<DecryptReader<R>> {
+        let mut nonce_prefix = [0u8; NONCE_PREFIX_SIZE];
+
+        inner.read_exact(&mut nonce_prefix)?;
+
+        Ok(DecryptReader {
+            inner,
+            aead: XChaCha20Poly1305::new(&self.content_key.into()),
+            nonce_prefix,
+            counter: 0,
+            buffer: Vec::new(),
+            position: 0,
+            finished: false,
+        })
+    }
+}
+
+/// Function to build the nonce of an encrypted name from its synthetic IV.
+fn name_nonce(siv: &[u8]) -> XNonce {
+    let mut nonce = [0u8; 24];
+
+    nonce[..SIV_SIZE].copy_from_slice(siv);
+
+    nonce.into()
+}
+
+/// Function to build the nonce of a segment of an encrypted stream, the last segment has a different nonce so a truncated stream is detected.
+fn segment_nonce(nonce_prefix: &[u8; NONCE_PREFIX_SIZE], counter: u32, last: bool) -> XNonce {
+    let mut nonce = [0u8; 24];
+
+    nonce[..NONCE_PREFIX_SIZE].copy_from_slice(nonce_prefix);
+    nonce[NONCE_PREFIX_SIZE..NONCE_PREFIX_SIZE + 4].copy_from_slice(&counter.to_be_bytes());
+    nonce[23] = last as u8;
+
+    nonce.into()
+}
+
+/// Writer encrypting the data in authenticated segments, contains five fields.
+/// * inner: the writer receiving the encrypted stream.
+/// * aead: the cipher used for every segment.
+/// * nonce_prefix: the random prefix of the nonces of this stream.
+/// * counter: the number of segments already written.
+/// * buffer: the plaintext of the segment being filled.
+pub struct EncryptWriter<W: Write> {
+    inner: W,
+    aead: XChaCha20Poly1305,
+    nonce_prefix: [u8; NONCE_PREFIX_SIZE],
+    counter: u32,
+    buffer: Vec<u8>,
+}
+
+impl<W: Write> EncryptWriter<W> {
+    fn write_segment(&mut self, last: bool) -> std::io::Result<()> {
+        let nonce = segment_nonce(&self.nonce_prefix, self.counter, last);
+        let ciphertext = self
+            .aead
+            .encrypt(&nonce, self.buffer.as_slice())
+            .map_err(|_| std::io::Error::other("Unable to encrypt the segment"))?;
+
+        self.inner.write_all(&ciphertext)?;
+        self.buffer.clear();
+        self.counter += 1;
+
+        Ok(())
+    }
+
+    /// Function to write the last segment, it returns the inner writer.
+    /// The last segment is always shorter than a full one, it can be empty.
+    pub fn finish(mut self) -> std::io::Result<W> {
+        if self.buffer.len() == SEGMENT_SIZE {
+            self.write_segment(false)?;
+        }
+
+        self.write_segment(true)?;
+        self.inner.flush()?;
+
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for EncryptWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if self.buffer.len() == SEGMENT_SIZE {
+            self.write_segment(false)?;
+        }
+
+        let written = buf.len().min(SEGMENT_SIZE - self.buffer.len());
+
+        self.buffer.extend_from_slice(&buf[..written]);
+
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Reader decrypting a stream written by an EncryptWriter, contains seven fields.
+/// * inner: the reader of the encrypted stream.
+/// * aead: the cipher used for every segment.
+/// * nonce_prefix: the random prefix of the nonces of this stream.
+/// * counter: the number of segments already read.
+/// * buffer: the plaintext of the current segment.
+/// * position: the amount of the current segment already returned.
+/// * finished: true once the last segment has been read.
+pub struct DecryptReader<R: Read> {
+    inner: R,
+    aead: XChaCha20Poly1305,
+    nonce_prefix: [u8; NONCE_PREFIX_SIZE],
+    counter: u32,
+    buffer: Vec<u8>,
+    position: usize,
+    finished: bool,
+}
+
+impl<R: Read> DecryptReader<R> {
+    fn read_segment(&mut self) -> std::io::Result<()> {
+        let mut ciphertext = vec![0u8; SEGMENT_SIZE + TAG_SIZE];
+        let mut filled = 0;
+
+        while filled < ciphertext.len() {
+            let read = self.inner.read(&mut ciphertext[filled..])?;
+
+            if read == 0 {
+                break;
+            }
+
+            filled += read;
+        }
+
+        // Only the last segment is shorter than a full one.
+        let last = filled < ciphertext.len();
+        let nonce = segment_nonce(&self.nonce_prefix, self.counter, last);
+
+        self.buffer = self
+            .aead
+            .decrypt(&nonce, &ciphertext[..filled])
+            .map_err(|_| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    "The encrypted stream is corrupted or truncated",
+                )
+            })?;
+        self.position = 0;
+        self.counter += 1;
+        self.finished = last;
+
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for DecryptReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        while self.position == self.buffer.len() {
+            if self.finished {
+                return Ok(0);
+            }
+
+            self.read_segment()?;
+        }
+
+        let read = buf.len().min(self.buffer.len() - self.position);
+
+        buf[..read].copy_from_slice(&self.buffer[self.position..self.position + read]);
+        self.position += read;
+
+        Ok(read)
+    }
+}
+
+/// Function to encode bytes in lowercase base32 without padding.
+fn base32_encode(data: &[u8]) -> String {
+    let mut encoded = String::new();
+    let mut bits: u32 = 0;
+    let mut n_bits = 0;
+
+    for byte in data {
+        bits = (bits << 8) | *byte as u32;
+        n_bits += 8;
+
+        while n_bits >= 5 {
+            n_bits -= 5;
+            encoded.push(BASE32_ALPHABET[((bits >> n_bits) & 31) as usize] as char);
+        }
+    }
+
+    if n_bits > 0 {
+        encoded.push(BASE32_ALPHABET[((bits << (5 - n_bits)) & 31) as usize] as char);
+    }
+
+    encoded
+}
+
+/// Function to decode a lowercase base32 string without padding.
+fn base32_decode(encoded: &str) -> Option<Vec<u8>> {
+    let mut data = Vec::new();
+    let mut bits: u32 = 0;
+    let mut n_bits = 0;
+
+    for c in encoded.bytes() {
+        let value = BASE32_ALPHABET.iter().position(|a| *a == c)? as u32;
+
+        bits = (bits << 5) | value;
+        n_bits += 5;
+
+        if n_bits >= 8 {
+            n_bits -= 8;
+            data.push((bits >> n_bits) as u8);
+        }
+    }
+
+    Some(data)
+}
+
+/// Function to derive the master key from the configured encryption.
+fn derive_master_key(encryption: &Encryption, salt: &[u8]) -> Result<[u8; 32], CryptoError> {
+    let mut master_key = [0u8; 32];
+
+    match encryption {
+        Encryption::Passphrase(passphrase) => argon2::Argon2::default()
+            .hash_password_into(passphrase.as_bytes(), salt, &mut master_key)
+            .map_err(|_| CryptoError::Corrupted)?,
+        Encryption::Keychain => {
+            let passphrase = read_keychain_passphrase().ok_or(CryptoError::PassphraseNotFound)?;
+
+            argon2::Argon2::default()
+                .hash_password_into(passphrase.as_bytes(), salt, &mut master_key)
+                .map_err(|_| CryptoError::Corrupted)?
+        }
+        Encryption::KeyFile(key_file) => {
+            let key = std::fs::read(key_file).map_err(|_| CryptoError::KeyFileNotFound)?;
+
+            master_key = blake3::derive_key("eb-rs key file", &key);
+        }
+    }
+
+    Ok(master_key)
+}
+
+/// Function to compute the keyed hash used to check that the master key is the right one.
+fn key_check(master_key: &[u8; 32]) -> String {
+    blake3::keyed_hash(master_key, b"eb-rs key check")
+        .to_hex()
+        .to_string()
+}
+
+/// Function to get the cipher of a device from the configured encryption.
+/// The first time a device is used the key record is created, if create is true, with a new random salt.
+/// The following times the record is used to derive the same key and to reject a wrong passphrase or key file.
+pub fn load_cipher(
+    device_path: &Path,
+    encryption: &Encryption,
+    create: bool,
+) -> Result<Cipher, CryptoError> {
+    let key_path = device_path.join(KEY_FILE_NAME);
+    let kdf = match encryption {
+        // The passphrase can move between the configuration file and the Keychain, the key stays the same.
+        Encryption::Passphrase(_) | Encryption::Keychain => "argon2id",
+        Encryption::KeyFile(_) => "key-file",
+    };
+
+    if let Ok(data) = std::fs::read_to_string(&key_path) {
+        let record: KeyRecord = serde_json::from_str(&data).map_err(|_| CryptoError::Corrupted)?;
+        let salt = hex::decode(&record.salt).map_err(|_| CryptoError::Corrupted)?;
+
+        if record.kdf != kdf {
+            return Err(CryptoError::WrongKey);
+        }
+
+        let master_key = derive_master_key(encryption, &salt)?;
+
+        if key_check(&master_key) != record.check {
+            return Err(CryptoError::WrongKey);
+        }
+
+        return Ok(Cipher::new(&master_key));
+    }
+
+    if !create {
+        return Err(CryptoError::KeyNotFound);
+    }
+
+    let mut salt = [0u8; 16];
+
+    OsRng.fill_bytes(&mut salt);
+
+    let master_key = derive_master_key(encryption, &salt)?;
+    let record = KeyRecord {
+        kdf: kdf.to_string(),
+        salt: hex::encode(salt),
+        check: key_check(&master_key),
+    };
+
+    std::fs::write(&key_path, serde_json::to_string_pretty(&record).unwrap())
+        .map_err(|_| CryptoError::KeyCreationError)?;
+
+    Ok(Cipher::new(&master_key))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_long_name_is_split_across_components_and_joined_back() {
+        let cipher = Cipher::new(&[7; 32]);
+        let path = Path::new("Documents")
+            .join("é".repeat(127) + "a")
+            .join("notes.txt");
+        let encrypted_path = cipher.encrypt_path(&path).unwrap();
+
+        // The 255 bytes long name needs two components, the others one each.
+        assert_eq!(encrypted_path.components().count(), 4);
+        assert!(encrypted_path
+            .components()
+            .all(|component| component.as_os_str().len() <= MAX_COMPONENT_SIZE));
+        assert_eq!(cipher.decrypt_path(&encrypted_path).unwrap(), path);
+        assert_eq!(
+            cipher.decrypt_path(encrypted_path.parent().unwrap().parent().unwrap()),
+            Err(CryptoError::Corrupted)
+        );
+    }
+}
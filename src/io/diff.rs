@@ -0,0 +1,359 @@
+use crate::config::config::Config;
+use crate::io::catalog::{find_snapshot, snapshot_entries, SnapshotInfo};
+use crate::io::crypto::Cipher;
+use crate::io::io::{hash_reader, scan_sources};
+use crate::io::report::BackupError;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::File;
+use std::path::{Path, PathBuf};
+
+/// Name given to the configured folders when a snapshot is compared with them.
+const LIVE_SOURCES_NAME: &str = "live sources";
+
+/// Enum describing how a file changed between two states.
+/// * Added: the file exists only in the newer state.
+/// * Removed: the file exists only in the older state.
+/// * Modified: the file exists in both states with a different size, modification time or content.
+/// * Renamed: the file has the same content in both states under another path.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Change {
+    Added,
+    Removed,
+    Modified,
+    Renamed,
+}
+
+/// Enum describing what differs in a modified file.
+/// * Size: the size of the file.
+/// * Mtime: the modification time of the file.
+/// * Content: the hash of the content of the file.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Difference {
+    Size,
+    Mtime,
+    Content,
+}
+
+/// Structure describing a changed file, contains five fields.
+/// * change: how the file changed.
+/// * path: the path of the file, relative to the snapshot folder, in the newer state if it still exists.
+/// * from: the path of a renamed file in the older state.
+/// * size: the size of the file in the newer state, or in the older one if it has been removed.
+/// * differences: what differs in a modified file.
+#[derive(Debug, PartialEq, Eq, Clone, Serialize)]
+pub struct FileChange {
+    change: Change,
+    path: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    from: Option<String>,
+    size: u64,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    differences: Vec<Difference>,
+}
+
+impl std::fmt::Display for FileChange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.change {
+            Change::Added => write!(f, "+ {}", self.path),
+            Change::Removed => write!(f, "- {}", self.path),
+            Change::Modified => write!(
+                f,
+                "M {} ({})",
+                self.path,
+                self.differences
+                    .iter()
+                    .map(|difference| match difference {
+                        Difference::Size => "size",
+                        Difference::Mtime => "mtime",
+                        Difference::Content => "content",
+                    })
+                    .collect::<Vec<&str>>()
+                    .join(", ")
+            ),
+            Change::Renamed => write!(
+                f,
+                "R {} -> {}",
+                self.from.clone().unwrap_or_default(),
+                self.path
+            ),
+        }
+    }
+}
+
+/// Structure with the changes between two states of the backed up files, contains three fields.
+/// * from: the name of the older state, a snapshot.
+/// * to: the name of the newer state, a snapshot or the live sources.
+/// * changes: the changed files, sorted by path.
+#[derive(Debug, PartialEq, Eq, Clone, Serialize)]
+pub struct DiffReport {
+    from: String,
+    to: String,
+    changes: Vec<FileChange>,
+}
+
+impl DiffReport {
+    /// Function to count the changes of the given kind.
+    fn count(&self, change: Change) -> usize {
+        self.changes
+            .iter()
+            .filter(|file_change| file_change.change == change)
+            .count()
+    }
+
+    /// Function to write the report as json, for other tools.
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).unwrap()
+    }
+}
+
+impl std::fmt::Display for DiffReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Changes from {} to {}: {} added, {} removed, {} modified, {} renamed",
+            self.from,
+            self.to,
+            self.count(Change::Added),
+            self.count(Change::Removed),
+            self.count(Change::Modified),
+            self.count(Change::Renamed)
+        )?;
+
+        for file_change in self.changes.iter() {
+            write!(f, "\n{}", file_change)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Structure describing a file of one of the compared states, contains four fields.
+/// * size: the size of the file in bytes.
+/// * modified: the modification time of the file, in nanoseconds since the unix epoch.
+/// * hash: the BLAKE3 hash of the content, the one of a live file is only computed when it's needed.
+/// * source: the path of a live file, to hash it.
+struct DiffFile {
+    size: u64,
+    modified: u64,
+    hash: Option<String>,
+    source: Option<PathBuf>,
+}
+
+impl DiffFile {
+    /// Function to get the hash of the content of the file, a live file is read the first time.
+    /// It returns None if the live file can't be read anymore.
+    fn get_hash(&mut self) -> Option<String> {
+        if self.hash.is_none() {
+            self.hash = self
+                .source
+                .as_ref()
+                .and_then(|source| hash_reader(&mut File::open(source).ok()?).ok());
+        }
+
+        self.hash.clone()
+    }
+}
+
+/// Function to list the files of a snapshot to compare them, by path.
+fn snapshot_files(
+    device_path: &Path,
+    snapshot: &SnapshotInfo,
+    cipher: Option<&Cipher>,
+) -> Result<BTreeMap<String, DiffFile>, BackupError> {
+    Ok(snapshot_entries(device_path, snapshot, cipher)?
+        .into_iter()
+        .map(|entry| {
+            (
+                entry.get_path(),
+                DiffFile {
+                    size: entry.get_size(),
+                    modified: entry.get_modified(),
+                    hash: Some(entry.get_hash()),
+                    source: None,
+                },
+            )
+        })
+        .collect())
+}
+
+/// Function to compare two states of the backed up files, given by path.
+/// Files with the same path are compared by size, modification time and content, a live file is hashed only when its
+/// size is the same but its modification time isn't, like the incremental backup does.
+/// A removed file and an added one with the same content are reported as a rename.
+fn compare(
+    from: String,
+    to: String,
+    mut old_files: BTreeMap<String, DiffFile>,
+    mut new_files: BTreeMap<String, DiffFile>,
+) -> DiffReport {
+    let mut changes = Vec::new();
+    let mut removed = Vec::new();
+    let mut added = Vec::new();
+
+    for (path, old_file) in old_files.iter_mut() {
+        let new_file = match new_files.get_mut(path) {
+            Some(new_file) => new_file,
+            None => {
+                removed.push(path.clone());
+                continue;
+            }
+        };
+        let mut differences = Vec::new();
+
+        if old_file.size != new_file.size {
+            differences.push(Difference::Size);
+        }
+
+        if old_file.modified != new_file.modified {
+            differences.push(Difference::Mtime);
+        }
+
+        // The hashes of two snapshots are already known, they are always compared.
+        let content_differs = if old_file.size != new_file.size {
+            true
+        } else if !differences.is_empty() || new_file.source.is_none() {
+            old_file.get_hash() != new_file.get_hash()
+        } else {
+            false
+        };
+
+        if content_differs {
+            differences.push(Difference::Content);
+        }
+
+        if !differences.is_empty() {
+            changes.push(FileChange {
+                change: Change::Modified,
+                path: path.clone(),
+                from: None,
+                size: new_file.size,
+                differences,
+            });
+        }
+    }
+
+    for path in new_files.keys() {
+        if !old_files.contains_key(path) {
+            added.push(path.clone());
+        }
+    }
+
+    // Only the added files as big as a removed one can be a rename, the other live files are not hashed.
+    let mut renamed_from = HashMap::new();
+
+    for path in removed.iter() {
+        let old_file = old_files.get_mut(path).unwrap();
+
+        if let Some(hash) = old_file.get_hash() {
+            renamed_from
+                .entry((old_file.size, hash))
+                .or_insert_with(Vec::new)
+                .push(path.clone());
+        }
+    }
+
+    for path in added {
+        let new_file = new_files.get_mut(&path).unwrap();
+        let from = if renamed_from.keys().any(|(size, _)| *size == new_file.size) {
+            new_file
+                .get_hash()
+                .and_then(|hash| renamed_from.get_mut(&(new_file.size, hash)))
+                .and_then(|paths| paths.pop())
+        } else {
+            None
+        };
+
+        changes.push(FileChange {
+            change: if from.is_some() {
+                Change::Renamed
+            } else {
+                Change::Added
+            },
+            path,
+            from,
+            size: new_file.size,
+            differences: Vec::new(),
+        });
+    }
+
+    let renamed = changes
+        .iter()
+        .filter_map(|file_change| file_change.from.clone())
+        .collect::<HashSet<String>>();
+
+    for path in removed.into_iter().filter(|path| !renamed.contains(path)) {
+        changes.push(FileChange {
+            change: Change::Removed,
+            size: old_files[&path].size,
+            path,
+            from: None,
+            differences: Vec::new(),
+        });
+    }
+
+    changes.sort_by(|a, b| a.path.cmp(&b.path));
+
+    DiffReport { from, to, changes }
+}
+
+/// Function to compare two snapshots of the device, the first one is the older.
+pub fn diff_snapshots(
+    device_path: &Path,
+    from_name: &str,
+    to_name: &str,
+    cipher: Option<&Cipher>,
+) -> Result<DiffReport, BackupError> {
+    let mut files = Vec::new();
+
+    for name in [from_name, to_name] {
+        let snapshot = find_snapshot(device_path, name)
+            .ok_or(BackupError::SnapshotNotFound(name.to_string()))?;
+
+        files.push((
+            snapshot.get_name(),
+            snapshot_files(device_path, &snapshot, cipher)?,
+        ));
+    }
+
+    let (to, new_files) = files.pop().unwrap();
+    let (from, old_files) = files.pop().unwrap();
+
+    Ok(compare(from, to, old_files, new_files))
+}
+
+/// Function to compare a snapshot of the device with the files of the configured paths, as the next backup would see them.
+pub fn diff_source(
+    configuration: &Config,
+    device_path: &Path,
+    snapshot_name: &str,
+    cipher: Option<&Cipher>,
+) -> Result<DiffReport, BackupError> {
+    let snapshot = find_snapshot(device_path, snapshot_name)
+        .ok_or(BackupError::SnapshotNotFound(snapshot_name.to_string()))?;
+    let old_files = snapshot_files(device_path, &snapshot, cipher)?;
+    let new_files = scan_sources(configuration, &configuration.get_source_paths())
+        .0
+        .into_iter()
+        .map(|file| {
+            (
+                file.get_relative_path().to_string_lossy().to_string(),
+                DiffFile {
+                    size: file.get_size(),
+                    modified: file.get_modified(),
+                    hash: None,
+                    source: Some(file.get_source()),
+                },
+            )
+        })
+        .collect();
+
+    Ok(compare(
+        snapshot.get_name(),
+        LIVE_SOURCES_NAME.to_string(),
+        old_files,
+        new_files,
+    ))
+}
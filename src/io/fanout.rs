@@ -0,0 +1,238 @@
+use crate::io::io::SourceFile;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Cursor, Read};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+use std::sync::{Condvar, Mutex};
+
+/// Size of the blocks a source file is read in and sent to the devices, 1 MiB.
+const BLOCK_SIZE: usize = 1024 * 1024;
+/// Number of blocks waiting to be written by a device, a slower device makes the reading wait for it.
+const QUEUED_BLOCKS: usize = 4;
+
+/// A block of a source file sent to a device, or the error that stopped the reading.
+type Block = std::io::Result<Vec<u8>>;
+
+/// Structure shared between the thread reading the sources and the devices writing them, contains two fields.
+/// * next: for every device, the position of the first file it may still ask for, `usize::MAX` once it's done.
+/// * requests: for every device, the position of the file it has asked for, with the channel of its blocks.
+struct TeeState {
+    next: Vec<usize>,
+    requests: Vec<Option<(usize, SyncSender<Block>)>>,
+}
+
+/// Structure reading the source files once for several devices, contains four fields.
+/// * paths: the source files, in the order they are read.
+/// * positions: the position of every source file in the order.
+/// * state: the files asked for by the devices.
+/// * changed: notified every time a device asks for a file or is done.
+///
+/// The devices ask for the files in the order they are read, each one skipping the files it doesn't need. A file is
+/// read once every device has asked for it or has gone past it, and its blocks are sent to the devices that asked for it.
+pub struct SourceTee {
+    paths: Vec<PathBuf>,
+    positions: HashMap<PathBuf, usize>,
+    state: Mutex<TeeState>,
+    changed: Condvar,
+}
+
+impl SourceTee {
+    pub fn new(files: &[SourceFile], devices: usize) -> Self {
+        let paths = files
+            .iter()
+            .map(|file| file.get_source())
+            .collect::<Vec<PathBuf>>();
+        let positions = paths
+            .iter()
+            .enumerate()
+            .map(|(position, path)| (path.clone(), position))
+            .collect();
+
+        SourceTee {
+            paths,
+            positions,
+            state: Mutex::new(TeeState {
+                next: vec![0; devices],
+                requests: (0..devices).map(|_| None).collect(),
+            }),
+            changed: Condvar::new(),
+        }
+    }
+
+    /// Function to get the sources of a device, the files it opens are read by the tee.
+    pub fn sources(&self, device: usize) -> Sources<'_> {
+        Sources {
+            tee: Some((self, device)),
+        }
+    }
+
+    /// Function to ask for a file on behalf of a device, it returns None if the device has already gone past it.
+    fn request(&self, device: usize, path: &Path) -> Option<TeeReader> {
+        let position = *self.positions.get(path)?;
+        let mut state = self.state.lock().unwrap();
+
+        if position < state.next[device] {
+            return None;
+        }
+
+        let (sender, receiver) = sync_channel(QUEUED_BLOCKS);
+
+        state.next[device] = position + 1;
+        state.requests[device] = Some((position, sender));
+        self.changed.notify_all();
+
+        Some(TeeReader {
+            blocks: receiver,
+            current: Cursor::new(Vec::new()),
+        })
+    }
+
+    /// Function to record that a device won't ask for any other file.
+    fn finish(&self, device: usize) {
+        let mut state = self.state.lock().unwrap();
+
+        state.next[device] = usize::MAX;
+        state.requests[device] = None;
+        self.changed.notify_all();
+    }
+
+    /// Function to read the source files in order, each one once, sending its blocks to the devices that asked for it.
+    /// A file no device asked for is not read. An error reading a file is sent to every device that asked for it,
+    /// a device that stops reading a file doesn't stop the others. It returns once every file has been gone through,
+    /// with the number of files read.
+    pub fn run(&self) -> usize {
+        let mut read_files = 0;
+
+        for (position, path) in self.paths.iter().enumerate() {
+            let mut senders = {
+                let mut state = self
+                    .changed
+                    .wait_while(self.state.lock().unwrap(), |state| {
+                        state.next.iter().any(|next| *next <= position)
+                    })
+                    .unwrap();
+
+                state
+                    .requests
+                    .iter_mut()
+                    .filter(|request| {
+                        request
+                            .as_ref()
+                            .is_some_and(|(asked, _)| *asked == position)
+                    })
+                    .filter_map(|request| request.take().map(|(_, sender)| sender))
+                    .collect::<Vec<SyncSender<Block>>>()
+            };
+
+            if senders.is_empty() {
+                continue;
+            }
+
+            read_files += 1;
+
+            let mut source = match File::open(path) {
+                Ok(source) => source,
+                Err(err) => {
+                    send_error(&senders, &err);
+                    continue;
+                }
+            };
+
+            // The channels are dropped at the end of the file, the devices read it as the end of the content.
+            while !senders.is_empty() {
+                let mut block = vec![0u8; BLOCK_SIZE];
+
+                let read = match source.read(&mut block) {
+                    Ok(0) => break,
+                    Ok(read) => read,
+                    Err(err) if err.kind() == std::io::ErrorKind::Interrupted => continue,
+                    Err(err) => {
+                        send_error(&senders, &err);
+                        break;
+                    }
+                };
+
+                block.truncate(read);
+                // A device that has dropped its reader, because it failed writing the file, is left out.
+                senders.retain(|sender| sender.send(Ok(block.clone())).is_ok());
+            }
+        }
+
+        read_files
+    }
+}
+
+/// Function to send the error reading a source file to the devices that asked for it.
+fn send_error(senders: &[SyncSender<Block>], err: &std::io::Error) {
+    for sender in senders {
+        let _ = sender.send(Err(std::io::Error::new(err.kind(), err.to_string())));
+    }
+}
+
+/// Reader of a source file read by the tee, the blocks are received one at a time while the file is read.
+/// It contains two fields.
+/// * blocks: the blocks of the file not received yet, the channel is closed at the end of the file.
+/// * current: the block being read.
+struct TeeReader {
+    blocks: Receiver<Block>,
+    current: Cursor<Vec<u8>>,
+}
+
+impl Read for TeeReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        loop {
+            let read = self.current.read(buf)?;
+
+            if read > 0 || buf.is_empty() {
+                return Ok(read);
+            }
+
+            match self.blocks.recv() {
+                Ok(block) => self.current = Cursor::new(block?),
+                Err(_) => return Ok(0),
+            }
+        }
+    }
+}
+
+/// Structure opening the source files for the copy engine of a device, contains one field.
+/// * tee: the tee reading the sources for every device, with the device, or None if the sources are opened directly.
+///
+/// Once the device is done with the sources, or the structure is dropped, the tee stops waiting for it.
+pub struct Sources<'a> {
+    tee: Option<(&'a SourceTee, usize)>,
+}
+
+impl Sources<'_> {
+    /// Function to get the sources of a backup written to a single device, every file is opened directly.
+    pub fn direct() -> Self {
+        Sources { tee: None }
+    }
+
+    /// Function to check if the sources are read once for several devices, the files must then be opened in order.
+    pub fn is_shared(&self) -> bool {
+        self.tee.is_some()
+    }
+
+    /// Function to open a source file. A file opened out of order, which the tee has already gone past, is opened directly.
+    pub fn open(&self, path: &Path) -> std::io::Result<Box<dyn Read + Send>> {
+        match self.tee.and_then(|(tee, device)| tee.request(device, path)) {
+            Some(reader) => Ok(Box::new(reader)),
+            None => Ok(Box::new(File::open(path)?)),
+        }
+    }
+
+    /// Function to record that the device won't open any other source file, so the tee doesn't wait for it.
+    pub fn finish(&self) {
+        if let Some((tee, device)) = self.tee {
+            tee.finish(device);
+        }
+    }
+}
+
+impl Drop for Sources<'_> {
+    fn drop(&mut self) {
+        self.finish();
+    }
+}
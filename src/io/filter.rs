@@ -0,0 +1,202 @@
+use crate::config::config::{Config, ExcludePreset, SourcePath};
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use ignore::Match;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+/// Name of the files listing gitignore-style patterns of the files not to back up, in the folder they apply to.
+pub const IGNORE_FILE_NAME: &str = ".ebignore";
+/// Name of the file tagging a folder as a cache, see https://bford.info/cachedir/.
+const CACHEDIR_TAG_FILE_NAME: &str = "CACHEDIR.TAG";
+/// Header a `CACHEDIR.TAG` file starts with, a file without it doesn't tag its folder.
+const CACHEDIR_TAG_SIGNATURE: &[u8] = b"Signature: 8a477f597d28d172789f06886806bc55";
+/// Patterns of the developer caches preset.
+const DEVELOPER_CACHES_PATTERNS: &[&str] = &[
+    "node_modules/",
+    ".venv/",
+    "__pycache__/",
+    ".pytest_cache/",
+    ".mypy_cache/",
+    ".tox/",
+    ".gradle/",
+    ".next/",
+];
+/// Build folders of the developer caches preset, each one is only excluded next to the project file of its tool,
+/// a folder with the same name elsewhere may hold anything.
+const DEVELOPER_BUILD_DIRS: &[(&str, &str)] = &[
+    ("target", "Cargo.toml"),
+    ("target", "pom.xml"),
+    (".build", "Package.swift"),
+];
+/// Patterns of the OS caches preset.
+const OS_CACHES_PATTERNS: &[&str] = &[
+    "**/Library/Caches/",
+    ".Trash/",
+    ".Trashes/",
+    ".Spotlight-V100/",
+    ".fseventsd/",
+    ".TemporaryItems/",
+    ".DS_Store",
+    "._*",
+];
+
+/// Filter of the files of a configured folder, it follows the gitignore semantics, contains four fields.
+/// * include: the patterns of the files to back up, if any.
+/// * excludes: the patterns of the files not to back up, the ones of the presets and the configured ones first,
+///   then the `.ebignore` files of the folders being scanned, from the outermost.
+/// * build_dirs: the build folders excluded next to the project file of their tool.
+/// * exclude_cache_dirs: if the folders tagged with a `CACHEDIR.TAG` file are excluded.
+pub struct SourceFilter {
+    include: Option<Gitignore>,
+    excludes: Vec<Gitignore>,
+    build_dirs: Vec<(&'static str, &'static str)>,
+    exclude_cache_dirs: bool,
+}
+
+/// Function to check if a folder is tagged as a cache, with a `CACHEDIR.TAG` file starting with the standard signature.
+fn is_cache_dir(dir: &Path) -> bool {
+    let mut header = [0; CACHEDIR_TAG_SIGNATURE.len()];
+
+    File::open(dir.join(CACHEDIR_TAG_FILE_NAME))
+        .and_then(|mut tag_file| tag_file.read_exact(&mut header))
+        .is_ok_and(|_| header == CACHEDIR_TAG_SIGNATURE)
+}
+
+/// Function to build a matcher of the given patterns, relative to the root folder.
+/// The invalid patterns are left out and their errors are returned.
+fn build_matcher(root: &Path, patterns: &[String]) -> (Gitignore, Vec<String>) {
+    let mut builder = GitignoreBuilder::new(root);
+    let mut errors = Vec::new();
+
+    for pattern in patterns {
+        if let Err(err) = builder.add_line(None, pattern) {
+            errors.push(format!("invalid pattern, {}", err));
+        }
+    }
+
+    match builder.build() {
+        Ok(matcher) => (matcher, errors),
+        Err(err) => {
+            errors.push(format!("invalid pattern, {}", err));
+
+            (Gitignore::empty(), errors)
+        }
+    }
+}
+
+impl SourceFilter {
+    /// SourceFilter struct constructor, with the patterns of the configured path and the exclusions of the configuration.
+    /// The configured patterns come after the ones of the presets, so `!` patterns can include again what a preset excludes.
+    /// It also returns the errors of the invalid patterns, which are left out.
+    pub fn new(configuration: &Config, source_path: &SourcePath) -> (SourceFilter, Vec<String>) {
+        let root = Path::new(&source_path.get_path()).to_path_buf();
+        let presets = configuration.get_exclude_presets();
+        let mut patterns = Vec::new();
+        let mut build_dirs = Vec::new();
+
+        for preset in presets.iter() {
+            match preset {
+                ExcludePreset::DeveloperCaches => {
+                    patterns.extend(
+                        DEVELOPER_CACHES_PATTERNS
+                            .iter()
+                            .map(|line| line.to_string()),
+                    );
+                    build_dirs.extend_from_slice(DEVELOPER_BUILD_DIRS);
+                }
+                ExcludePreset::OsCaches => {
+                    patterns.extend(OS_CACHES_PATTERNS.iter().map(|line| line.to_string()))
+                }
+            }
+        }
+
+        patterns.extend(source_path.get_exclude());
+
+        let mut errors = Vec::new();
+        let include = if source_path.get_include().is_empty() {
+            None
+        } else {
+            let (matcher, include_errors) = build_matcher(&root, &source_path.get_include());
+
+            errors.extend(include_errors);
+
+            Some(matcher)
+        };
+        let (exclude, exclude_errors) = build_matcher(&root, &patterns);
+
+        errors.extend(exclude_errors);
+
+        (
+            SourceFilter {
+                include,
+                excludes: vec![exclude],
+                build_dirs,
+                exclude_cache_dirs: configuration.get_exclude_cache_dirs(),
+            },
+            errors,
+        )
+    }
+
+    /// Function to check if a folder is a cache or a build folder, which can be rebuilt.
+    fn is_rebuildable_dir(&self, dir: &Path) -> bool {
+        let name = dir.file_name().unwrap_or_default();
+        let is_build_dir = self.build_dirs.iter().any(|(build_dir, project_file)| {
+            name == *build_dir
+                && dir
+                    .parent()
+                    .is_some_and(|parent| parent.join(project_file).is_file())
+        });
+
+        is_build_dir || (self.exclude_cache_dirs && is_cache_dir(dir))
+    }
+
+    /// Function to add the patterns of the `.ebignore` file of a folder, if there is one, before scanning it.
+    /// It returns if the patterns have been added, so they can be removed with **leave_dir** once the folder is scanned.
+    pub fn enter_dir(&mut self, dir: &Path) -> bool {
+        let ignore_path = dir.join(IGNORE_FILE_NAME);
+
+        if !ignore_path.is_file() {
+            return false;
+        }
+
+        // An unreadable line is skipped, the others still apply.
+        let (matcher, _) = Gitignore::new(ignore_path);
+
+        self.excludes.push(matcher);
+
+        true
+    }
+
+    /// Function to remove the patterns added by **enter_dir**, once the folder has been scanned.
+    pub fn leave_dir(&mut self, entered: bool) {
+        if entered {
+            self.excludes.pop();
+        }
+    }
+
+    /// Function to check if a file or a folder is excluded from the backup.
+    /// The patterns of the innermost `.ebignore` file matching the path decide, like nested `.gitignore` files.
+    /// The include patterns only apply to files, a file is included if it or one of its folders matches them.
+    /// Folders tagged as caches and build folders of the presets are always excluded.
+    pub fn is_excluded(&self, path: &Path, is_dir: bool) -> bool {
+        if is_dir && self.is_rebuildable_dir(path) {
+            return true;
+        }
+
+        for exclude in self.excludes.iter().rev() {
+            match exclude.matched(path, is_dir) {
+                Match::Ignore(_) => return true,
+                Match::Whitelist(_) => break,
+                Match::None => {}
+            }
+        }
+
+        match self.include.as_ref() {
+            Some(include) if !is_dir => !include
+                .matched_path_or_any_parents(path, is_dir)
+                .is_ignore(),
+            _ => false,
+        }
+    }
+}
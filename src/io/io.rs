@@ -1,9 +1,42 @@
+use crate::config::config::{BackupFormat, Config, SourcePath};
+use crate::io::archive::{
+    extract_archive, write_archive, ARCHIVE_FILE_NAME, ENCRYPTED_ARCHIVE_FILE_NAME,
+};
+use crate::io::crypto::{load_cipher, Cipher};
+use crate::io::fanout::{SourceTee, Sources};
+use crate::io::filter::SourceFilter;
+use crate::io::journal::{
+    find_partial, journal_sources, mark_complete, mark_partial, partial_path, read_journal, Journal,
+};
+use crate::io::manifest::{
+    Manifest, ManifestEntry, SnapshotEntry, SnapshotManifest, MANIFEST_FILE_NAME,
+};
+use crate::io::parallel::run_parallel;
+use crate::io::plan::{record_throughput, select_within, space_capacity, time_capacity};
+use crate::io::priority::sort_by_priority;
+use crate::io::progress::Progress;
+use crate::io::report::{BackupError, BackupIssue, BackupReport, DevicesReport};
+use crate::io::repository::{extract_snapshot, previous_files, store_snapshot, INDEX_FILE_NAME};
+use crate::io::retention::prune_snapshots;
+use crate::io::verify::{verify_snapshot, VerificationReport};
 use byte_unit::Byte;
 use chrono::prelude::Local;
-use fs_extra::{copy_items, dir::CopyOptions};
-use std::path::PathBuf;
+use log::info;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, UNIX_EPOCH};
 use sysinfo::Disks;
 
+/// Size of the buffer used to copy and hash files, 1 MiB.
+const COPY_BUFFER_SIZE: usize = 1024 * 1024;
+/// Format of the names of the snapshot folders, built from the time the backup starts.
+pub const SNAPSHOT_NAME_FORMAT: &str = "backup-%Y-%m-%d_%H-%M-%S";
+
 #[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone)]
 pub struct Device {
     name: String,
@@ -78,23 +111,1192 @@ pub fn get_ext_devices() -> Vec<Device> {
         .collect::<Vec<Device>>()
 }
 
-///
-pub fn execute_copy(device_name: String, path_names: Vec<String>) {
-    let wrap_dir = format!("backup-{}", Local::now().format("%Y-%m-%d_%H-%M-%S"));
-    let dst = PathBuf::from("/Volumes").join(device_name);
+/// Structure describing a regular file found inside the configured paths, contains four fields.
+/// * source: the absolute path of the file.
+/// * relative_path: the path inside the snapshot folder, it starts with the name of the configured path.
+/// * size: the size of the file in bytes.
+/// * modified: the modification time of the file, in nanoseconds since the unix epoch.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone)]
+pub struct SourceFile {
+    source: PathBuf,
+    relative_path: PathBuf,
+    size: u64,
+    modified: u64,
+}
+
+impl SourceFile {
+    pub fn new(source: PathBuf, relative_path: PathBuf, size: u64, modified: u64) -> SourceFile {
+        SourceFile {
+            source,
+            relative_path,
+            size,
+            modified,
+        }
+    }
+
+    pub fn get_source(&self) -> PathBuf {
+        self.source.clone()
+    }
+
+    pub fn get_relative_path(&self) -> PathBuf {
+        self.relative_path.clone()
+    }
+
+    pub fn get_size(&self) -> u64 {
+        self.size
+    }
 
-    if !dst.exists() {
-        return;
+    pub fn get_modified(&self) -> u64 {
+        self.modified
+    }
+}
+
+/// Function to convert the modification time of a file in nanoseconds since the unix epoch.
+pub fn modified_nanos(metadata: &std::fs::Metadata) -> u64 {
+    metadata
+        .modified()
+        .ok()
+        .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+        .map(|duration| duration.as_nanos() as u64)
+        .unwrap_or(0)
+}
+
+/// Function to list the regular files inside the configured paths, the paths can be both directories and files.
+/// Symbolic links to files are followed, symbolic links to directories are skipped to avoid cycles.
+/// Inside directories the files and folders excluded by the patterns of the path, by `.ebignore` files, by the presets or
+/// because they are tagged as caches are left out.
+/// It returns the files found and the ones skipped, with the reason.
+pub fn scan_sources(
+    configuration: &Config,
+    source_paths: &[SourcePath],
+) -> (Vec<SourceFile>, Vec<BackupIssue>) {
+    let mut files = Vec::new();
+    let mut skipped = Vec::new();
+
+    for source_path in source_paths {
+        let path = PathBuf::from(source_path.get_path());
+        let root_name = match path.file_name() {
+            Some(root_name) => PathBuf::from(root_name),
+            None => {
+                skipped.push(BackupIssue::new(&path, "not a valid path".to_string()));
+                continue;
+            }
+        };
+
+        match std::fs::metadata(&path) {
+            Ok(metadata) if metadata.is_dir() => {
+                let (mut filter, errors) = SourceFilter::new(configuration, source_path);
+
+                for err in errors {
+                    skipped.push(BackupIssue::new(&path, err));
+                }
+
+                scan_dir(
+                    &path,
+                    &root_name,
+                    Some(&mut filter),
+                    &mut files,
+                    &mut skipped,
+                )
+            }
+            Ok(metadata) if metadata.is_file() => files.push(SourceFile::new(
+                path,
+                root_name,
+                metadata.len(),
+                modified_nanos(&metadata),
+            )),
+            Ok(_) => skipped.push(BackupIssue::new(&path, "not a regular file".to_string())),
+            Err(err) => skipped.push(BackupIssue::new(&path, err.to_string())),
+        }
     }
 
-    let dst = dst.join(wrap_dir);
-    let _ = std::fs::create_dir(dst.clone());
-    let copy_options = CopyOptions::default().overwrite(true);
+    (files, skipped)
+}
 
-    match copy_items(path_names.as_slice(), dst, &copy_options) {
-        Ok(_) => {}
+/// Function to list the regular files inside a directory, the ones excluded by the filter, if any, are left out.
+fn scan_dir(
+    dir: &Path,
+    relative_dir: &Path,
+    mut filter: Option<&mut SourceFilter>,
+    files: &mut Vec<SourceFile>,
+    skipped: &mut Vec<BackupIssue>,
+) {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
         Err(err) => {
-            println!("{:?}", err);
+            skipped.push(BackupIssue::new(dir, err.to_string()));
+            return;
+        }
+    };
+    let entered = filter.as_mut().is_some_and(|filter| filter.enter_dir(dir));
+
+    for entry in entries.flatten() {
+        let path = entry.path();
+        let relative_path = relative_dir.join(entry.file_name());
+        let file_type = match entry.file_type() {
+            Ok(file_type) => file_type,
+            Err(err) => {
+                skipped.push(BackupIssue::new(&path, err.to_string()));
+                continue;
+            }
+        };
+
+        if filter
+            .as_ref()
+            .is_some_and(|filter| filter.is_excluded(&path, file_type.is_dir()))
+        {
+            continue;
+        }
+
+        if file_type.is_dir() {
+            scan_dir(&path, &relative_path, filter.as_deref_mut(), files, skipped);
+            continue;
+        }
+
+        match std::fs::metadata(&path) {
+            Ok(metadata) if metadata.is_file() => files.push(SourceFile::new(
+                path,
+                relative_path,
+                metadata.len(),
+                modified_nanos(&metadata),
+            )),
+            Ok(metadata) if metadata.is_dir() => skipped.push(BackupIssue::new(
+                &path,
+                "symbolic link to a directory".to_string(),
+            )),
+            Ok(_) => skipped.push(BackupIssue::new(&path, "not a regular file".to_string())),
+            Err(err) => skipped.push(BackupIssue::new(&path, err.to_string())),
+        }
+    }
+
+    if let Some(filter) = filter {
+        filter.leave_dir(entered);
+    }
+}
+
+/// Function to get the size of the files of a configured path that are backed up, the exclusions are applied like in a backup.
+pub fn source_size(configuration: &Config, source_path: &SourcePath) -> u64 {
+    scan_sources(configuration, std::slice::from_ref(source_path))
+        .0
+        .iter()
+        .map(|file| file.get_size())
+        .sum()
+}
+
+/// Function to count the files of a snapshot folder and their size on the device, the metadata files are not counted.
+pub fn snapshot_totals(snapshot_path: &Path) -> (usize, u64) {
+    let mut files = Vec::new();
+
+    scan_dir(
+        snapshot_path,
+        Path::new(""),
+        None,
+        &mut files,
+        &mut Vec::new(),
+    );
+
+    files
+        .iter()
+        .filter(|file| {
+            !file
+                .get_relative_path()
+                .to_string_lossy()
+                .starts_with(".eb-rs")
+        })
+        .fold((0, 0), |(count, size), file| {
+            (count + 1, size + file.get_size())
+        })
+}
+
+/// Function to get the space available on the disk holding the given path, in bytes.
+pub fn get_available_space(path: &Path) -> Option<u64> {
+    let disks = Disks::new_with_refreshed_list();
+
+    disks
+        .iter()
+        .filter(|disk| path.starts_with(disk.mount_point()))
+        .max_by_key(|disk| disk.mount_point().components().count())
+        .map(|disk| disk.available_space())
+}
+
+/// Function to get the path where a device is mounted.
+pub fn get_device_path(device_name: &str) -> PathBuf {
+    PathBuf::from("/Volumes").join(device_name)
+}
+
+/// Function to read a json metadata file from the device, the file is decrypted if a cipher is given.
+pub fn read_metadata<T: DeserializeOwned>(path: &Path, cipher: Option<&Cipher>) -> Option<T> {
+    let data = std::fs::read(path).ok()?;
+    let data = match cipher {
+        Some(cipher) => cipher.open(&data).ok()?,
+        None => data,
+    };
+
+    serde_json::from_slice(&data).ok()
+}
+
+/// Function to write a json metadata file on the device, the file is encrypted if a cipher is given.
+/// The file is written to a temporary file first and then renamed, so an interrupted write never corrupts the previous one.
+pub fn write_metadata<T: Serialize>(
+    path: &Path,
+    value: &T,
+    cipher: Option<&Cipher>,
+) -> std::io::Result<()> {
+    let data = serde_json::to_vec(value).unwrap();
+    let data = match cipher {
+        Some(cipher) => cipher.seal(&data),
+        None => data,
+    };
+    let tmp_path = path.with_extension("tmp");
+    let mut tmp_file = File::create(&tmp_path)?;
+
+    tmp_file.write_all(&data)?;
+    tmp_file.sync_all()?;
+
+    std::fs::rename(tmp_path, path)
+}
+
+/// Function to copy everything from a reader to a writer, computing the BLAKE3 hash of the data in the same pass.
+pub fn copy_hashed<R: Read + ?Sized, W: Write>(
+    reader: &mut R,
+    writer: &mut W,
+) -> std::io::Result<String> {
+    let mut hasher = blake3::Hasher::new();
+    let mut buffer = vec![0; COPY_BUFFER_SIZE];
+
+    loop {
+        let read = reader.read(&mut buffer)?;
+
+        if read == 0 {
+            break;
+        }
+
+        hasher.update(&buffer[..read]);
+        writer.write_all(&buffer[..read])?;
+    }
+
+    Ok(hasher.finalize().to_hex().to_string())
+}
+
+/// Function to read everything from a reader, it returns the BLAKE3 hash of the data.
+pub fn hash_reader<R: Read>(reader: &mut R) -> std::io::Result<String> {
+    copy_hashed(reader, &mut std::io::sink())
+}
+
+/// Function to copy a file computing the BLAKE3 hash of its content in the same pass, the copy is encrypted if a cipher is given.
+/// Permissions and modification time of the source are preserved, it returns the hex encoded hash of the source.
+/// The copy is on the device when the function returns, the bytes read are added to the progress.
+/// If the backup is cancelled while the file is copied an error is returned.
+pub fn copy_file_hashed(
+    src: &Path,
+    dst: &Path,
+    cipher: Option<&Cipher>,
+    sources: &Sources,
+    progress: &Progress,
+) -> std::io::Result<String> {
+    prepare_destination(dst)?;
+
+    let metadata = std::fs::metadata(src)?;
+    let mut src_file = progress
+        .get_cancellation_token()
+        .reader(progress.reader(sources.open(src)?));
+    let mut dst_file = File::create(dst)?;
+    let hash = match cipher {
+        Some(cipher) => {
+            let mut encrypt_writer = cipher.encrypt_writer(&mut dst_file)?;
+            let hash = copy_hashed(&mut src_file, &mut encrypt_writer)?;
+
+            encrypt_writer.finish()?;
+
+            hash
+        }
+        None => copy_hashed(&mut src_file, &mut dst_file)?,
+    };
+
+    dst_file.set_permissions(metadata.permissions())?;
+
+    if let Ok(modified) = metadata.modified() {
+        let _ = dst_file.set_modified(modified);
+    }
+
+    dst_file.sync_all()?;
+
+    Ok(hash)
+}
+
+/// Function to prepare the destination of a file, creating the missing parent directories.
+/// A file left by an interrupted backup is removed, since it may be a hard link to a previous snapshot that must not be overwritten.
+fn prepare_destination(dst: &Path) -> std::io::Result<()> {
+    if let Some(parent) = dst.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+
+    match std::fs::remove_file(dst) {
+        Err(err) if err.kind() != std::io::ErrorKind::NotFound => Err(err),
+        _ => Ok(()),
+    }
+}
+
+/// Function to create a hard link to a file of a previous snapshot, creating the missing parent directories.
+fn link_previous(previous: &Path, dst: &Path) -> std::io::Result<()> {
+    prepare_destination(dst)?;
+
+    std::fs::hard_link(previous, dst)
+}
+
+/// Function to get the cipher of the backups of a device, if the encryption is configured.
+fn open_device(configuration: &Config, device_path: &Path) -> Result<Option<Cipher>, BackupError> {
+    if !device_path.exists() {
+        return Err(BackupError::DeviceNotFound);
+    }
+
+    match configuration.get_encryption() {
+        Some(encryption) => Ok(Some(
+            load_cipher(device_path, &encryption, true).map_err(BackupError::EncryptionError)?,
+        )),
+        None => Ok(None),
+    }
+}
+
+/// Function to execute an incremental backup of the configured paths on every configured device that is attached.
+/// A new snapshot folder named after the current time is created and filled according to the backup format,
+/// if the last backup in the same format has been interrupted its snapshot is resumed instead.
+/// If the encryption is configured file contents, file names and metadata are encrypted.
+/// Once the files are written a manifest with their hashes is saved in the snapshot and every file is read back from the device.
+/// It returns the report of the backup on each device, files that can't be copied don't stop it and are listed in the report.
+/// The progress is updated while the snapshots are written, it's finished when the function returns.
+pub fn execute_copy(
+    configuration: &Config,
+    progress: &Progress,
+) -> Result<DevicesReport, BackupError> {
+    let result = run_backup(configuration, progress, false);
+
+    progress.finish();
+
+    result
+}
+
+/// Function to resume the last interrupted backup on the configured devices, the files already in the journal are not copied again.
+pub fn resume_copy(
+    configuration: &Config,
+    progress: &Progress,
+) -> Result<DevicesReport, BackupError> {
+    let result = run_backup(configuration, progress, true);
+
+    progress.finish();
+
+    result
+}
+
+/// Function to write a snapshot on every configured device, resuming the last interrupted one of each device if there is any.
+/// If only_resume is set the devices without an interrupted snapshot are not written.
+/// It fails only if none of the configured devices is attached, the devices not attached have their own error in the report.
+fn run_backup(
+    configuration: &Config,
+    progress: &Progress,
+    only_resume: bool,
+) -> Result<DevicesReport, BackupError> {
+    let start = Instant::now();
+    let device_names = configuration.get_device_names();
+    let devices = device_names
+        .iter()
+        .map(|device_name| (device_name.clone(), get_device_path(device_name)))
+        .filter(|(_, device_path)| device_path.exists())
+        .collect::<Vec<(String, PathBuf)>>();
+
+    if devices.is_empty() {
+        return Err(BackupError::DeviceNotFound);
+    }
+
+    let (mut files, skipped) = scan_sources(configuration, &configuration.get_source_paths());
+    let deadline = configuration
+        .get_time_budget()
+        .map(|time_budget| start + Duration::from_secs(time_budget));
+
+    sort_by_priority(&mut files, &configuration.get_folder_priorities());
+
+    if let Some(deadline) = deadline {
+        progress.set_deadline(deadline);
+    }
+
+    let mut results = write_devices(
+        configuration,
+        &devices,
+        &files,
+        &skipped,
+        deadline,
+        only_resume,
+        progress,
+    )
+    .into_iter();
+    let mut report = DevicesReport::default();
+
+    // The attached devices keep the configured order.
+    for device_name in device_names {
+        if devices.iter().any(|(name, _)| *name == device_name) {
+            report.add_result(device_name, results.next().unwrap());
+        } else {
+            report.add_result(device_name, Err(BackupError::DeviceNotFound));
+        }
+    }
+
+    Ok(report)
+}
+
+/// Function to write a snapshot of the scanned files on each of the devices, with the same name and the same deadline.
+/// With several devices they are written at the same time, one thread each, and every source file is read only once:
+/// its content is sent to every device that needs it, so the slowest device sets the pace. The subscribers of the
+/// progress follow the first device. A device that fails, as a whole or on a file, doesn't stop the others.
+/// It returns the result of each device, in the order of the devices.
+fn write_devices(
+    configuration: &Config,
+    devices: &[(String, PathBuf)],
+    files: &[SourceFile],
+    skipped: &[BackupIssue],
+    deadline: Option<Instant>,
+    only_resume: bool,
+    progress: &Progress,
+) -> Vec<Result<BackupReport, BackupError>> {
+    let snapshot_name = Local::now().format(SNAPSHOT_NAME_FORMAT).to_string();
+    let write_device = |device_path: &Path, sources: &Sources, progress: &Progress| {
+        let cipher = open_device(configuration, device_path)?;
+        let (snapshot_name, resumed) =
+            match find_partial(device_path, configuration.get_backup_format()) {
+                Some(partial_name) => (partial_name, true),
+                None if only_resume => return Err(BackupError::NothingToResume),
+                None => (snapshot_name.clone(), false),
+            };
+
+        write_snapshot(
+            configuration,
+            device_path,
+            &snapshot_name,
+            cipher.as_ref(),
+            resumed,
+            files,
+            skipped,
+            deadline,
+            sources,
+            progress,
+        )
+    };
+
+    if let [(_, device_path)] = devices {
+        return vec![write_device(device_path, &Sources::direct(), progress)];
+    }
+
+    let tee = SourceTee::new(files, devices.len());
+
+    std::thread::scope(|scope| {
+        let writers = devices
+            .iter()
+            .enumerate()
+            .map(|(device, (_, device_path))| {
+                let sources = tee.sources(device);
+                let progress = if device == 0 {
+                    progress.clone()
+                } else {
+                    progress.detached()
+                };
+
+                scope.spawn(move || write_device(device_path, &sources, &progress))
+            })
+            .collect::<Vec<_>>();
+        let read_files = tee.run();
+
+        info!(
+            "{} source files read once for {} devices",
+            read_files,
+            devices.len()
+        );
+
+        writers
+            .into_iter()
+            .map(|writer| writer.join().unwrap())
+            .collect()
+    })
+}
+
+/// Function to write the source files in a snapshot, the snapshot is written in a partial folder renamed once it's complete.
+/// The files are written in priority order, so an interrupted snapshot holds the most valuable ones.
+/// Every completed file is recorded in the journal of the partial folder, so if the backup is interrupted it can be resumed.
+/// The completion record in the final folder tells when the snapshot has been written, how many files it holds and
+/// if they passed the verification.
+///
+/// The sources are the files scanned by the caller, sorted by priority, with the ones skipped while scanning.
+/// With a time budget the copy is planned with the throughput of the device: the files that can't be copied in time are
+/// left out and the copy stops at the deadline. The snapshot is completed with the files written so far and marked as
+/// partial, the verification is skipped if the deadline has passed.
+///
+/// The space of the device is checked once the old snapshots have been pruned: if the files don't fit, the most valuable
+/// ones that fit are copied and the others are left out, the snapshot is marked as partial the same way.
+#[allow(clippy::too_many_arguments)]
+fn write_snapshot(
+    configuration: &Config,
+    device_path: &Path,
+    snapshot_name: &str,
+    cipher: Option<&Cipher>,
+    resumed: bool,
+    files: &[SourceFile],
+    skipped: &[BackupIssue],
+    deadline: Option<Instant>,
+    sources: &Sources,
+    progress: &Progress,
+) -> Result<BackupReport, BackupError> {
+    let start = Instant::now();
+    let mut report = BackupReport::new(device_path.join(snapshot_name), resumed);
+    let backup_format = configuration.get_backup_format();
+    let mut files = files.to_vec();
+    let snapshot_path = partial_path(device_path, snapshot_name);
+    let mut costs = copy_costs(device_path, &snapshot_path, backup_format, &files, cipher);
+
+    report.set_skipped(skipped.to_vec());
+
+    if let Some(deadline) = deadline {
+        if let Some(capacity) = time_capacity(device_path, deadline) {
+            let selected = select_within(&costs, capacity);
+
+            leave_out(
+                &mut files,
+                &mut costs,
+                &selected,
+                "it can't be copied within the time budget",
+                &mut report,
+            );
+        }
+    }
+
+    prune_snapshots(
+        device_path,
+        configuration.get_retention(),
+        costs.iter().sum(),
+        cipher,
+        &mut report,
+    );
+
+    if let Some(capacity) = space_capacity(device_path) {
+        if costs.iter().sum::<u64>() > capacity {
+            let selected = select_within(&costs, capacity);
+
+            leave_out(
+                &mut files,
+                &mut costs,
+                &selected,
+                "it doesn't fit on the device",
+                &mut report,
+            );
+        }
+    }
+
+    progress.start(files.len(), files.iter().map(|file| file.get_size()).sum());
+    mark_partial(&snapshot_path, backup_format)?;
+
+    let mut journal = Journal::open(&snapshot_path, cipher)?;
+    let copy_start = Instant::now();
+    // Sources read once for several devices come in order, one file at a time.
+    let parallel_copies = if sources.is_shared() {
+        1
+    } else {
+        configuration.get_parallel_copies()
+    };
+    let entries = match backup_format {
+        BackupFormat::Directory => copy_directory(
+            device_path,
+            snapshot_name,
+            &files,
+            cipher,
+            parallel_copies,
+            &mut journal,
+            &mut report,
+            sources,
+            progress,
+        ),
+        BackupFormat::Repository => store_snapshot(
+            device_path,
+            &snapshot_path,
+            &files,
+            cipher,
+            parallel_copies,
+            &mut journal,
+            &mut report,
+            sources,
+            progress,
+        )?,
+        BackupFormat::Archive => write_archive(
+            &snapshot_path,
+            &files,
+            configuration.get_compression_level(),
+            cipher,
+            &mut journal,
+            &mut report,
+            sources,
+            progress,
+        )?,
+    };
+
+    drop(journal);
+    // The other devices don't wait for this one while it's verified.
+    sources.finish();
+
+    // A device disconnected during the copy, or a cancelled backup, leaves the snapshot partial, so the next backup resumes it.
+    if !device_path.exists() {
+        return Err(BackupError::DeviceDisconnected);
+    }
+
+    if progress.is_cancelled() {
+        report.set_cancelled(snapshot_path);
+        report.set_totals(
+            entries.len(),
+            entries.iter().map(|entry| entry.get_size()).sum(),
+        );
+        report.set_duration(start.elapsed());
+
+        return Ok(report);
+    }
+
+    record_throughput(device_path, report.get_copied_bytes(), copy_start.elapsed());
+
+    // The files the copy engine didn't get to before the deadline, and didn't fail, are left out.
+    if progress.is_out_of_time() {
+        let written = entries
+            .iter()
+            .map(|entry| entry.get_source())
+            .collect::<HashSet<String>>();
+        let failed = report
+            .get_failures()
+            .iter()
+            .map(|failure| failure.get_path())
+            .collect::<HashSet<PathBuf>>();
+
+        for file in files.iter() {
+            if !written.contains(file.get_source().to_string_lossy().as_ref())
+                && !failed.contains(&file.get_source())
+            {
+                report.add_left_out(
+                    &file.get_source(),
+                    "the time budget has run out".to_string(),
+                );
+            }
         }
     }
+
+    let snapshot_manifest = SnapshotManifest::new(entries);
+
+    snapshot_manifest.save(&snapshot_path, cipher)?;
+
+    let verification = if progress.is_out_of_time() {
+        report.set_out_of_time();
+
+        VerificationReport::default()
+    } else {
+        progress.start_verification();
+
+        verify_snapshot(
+            device_path,
+            &snapshot_path,
+            backup_format,
+            &snapshot_manifest,
+            cipher,
+        )
+    };
+    let file_count = snapshot_manifest.get_entries().len();
+    let byte_count = snapshot_manifest
+        .get_entries()
+        .iter()
+        .map(|entry| entry.get_size())
+        .sum();
+
+    mark_complete(
+        device_path,
+        snapshot_name,
+        file_count,
+        byte_count,
+        verification.is_passed() && !report.is_out_of_time(),
+        report.is_partial(),
+    )?;
+
+    report.set_totals(file_count, byte_count);
+    report.set_verification(verification);
+    report.set_duration(start.elapsed());
+
+    Ok(report)
+}
+
+/// Function to estimate the bytes the next snapshot writes on the device for every source file.
+/// The files already in the journal of a resumed snapshot cost nothing. The files unchanged since the last backup cost
+/// nothing too, they are hard links in the directory format and reuse their chunks in the repository format.
+/// The archive format counts every other file.
+fn copy_costs(
+    device_path: &Path,
+    snapshot_path: &Path,
+    backup_format: BackupFormat,
+    files: &[SourceFile],
+    cipher: Option<&Cipher>,
+) -> Vec<u64> {
+    let completed = journal_sources(snapshot_path, backup_format, cipher);
+    let costs: Vec<u64> = match backup_format {
+        BackupFormat::Directory => {
+            let manifest = Manifest::load(device_path, cipher);
+
+            files
+                .iter()
+                .map(|file| {
+                    if manifest.is_unchanged(
+                        &file.get_source().to_string_lossy(),
+                        file.get_size(),
+                        file.get_modified(),
+                    ) {
+                        0
+                    } else {
+                        file.get_size()
+                    }
+                })
+                .collect()
+        }
+        BackupFormat::Repository => {
+            let previous = previous_files(device_path, snapshot_path, cipher)
+                .into_iter()
+                .map(|entry| (entry.get_source(), (entry.get_size(), entry.get_modified())))
+                .collect::<HashMap<String, (u64, u64)>>();
+
+            files
+                .iter()
+                .map(|file| {
+                    if previous.get(file.get_source().to_string_lossy().as_ref())
+                        == Some(&(file.get_size(), file.get_modified()))
+                    {
+                        0
+                    } else {
+                        file.get_size()
+                    }
+                })
+                .collect()
+        }
+        BackupFormat::Archive => files.iter().map(|file| file.get_size()).collect(),
+    };
+
+    files
+        .iter()
+        .zip(costs)
+        .map(|(file, cost)| {
+            if completed.contains(file.get_source().to_string_lossy().as_ref()) {
+                0
+            } else {
+                cost
+            }
+        })
+        .collect()
+}
+
+/// Function to leave out of the snapshot the files not selected, they are added to the report with the reason.
+fn leave_out(
+    files: &mut Vec<SourceFile>,
+    costs: &mut Vec<u64>,
+    selected: &[bool],
+    reason: &str,
+    report: &mut BackupReport,
+) {
+    let mut kept_files = Vec::new();
+    let mut kept_costs = Vec::new();
+
+    for ((file, cost), selected) in files.drain(..).zip(costs.drain(..)).zip(selected) {
+        if *selected {
+            kept_files.push(file);
+            kept_costs.push(cost);
+        } else {
+            report.add_left_out(&file.get_source(), reason.to_string());
+        }
+    }
+
+    *files = kept_files;
+    *costs = kept_costs;
+}
+
+/// Function to copy the source files in a snapshot folder as a plain directory tree.
+/// New files and files changed since the last backup are copied,
+/// unchanged files are hard links to their copy in the previous snapshot, like rsync `--link-dest`.
+/// Every snapshot is a complete directory tree; if the device doesn't support hard links the unchanged files are copied again.
+/// The manifest on the device keeps track of the last copy of every file and of the deleted ones.
+/// With a cipher file names, contents and the manifest are encrypted. It returns the files written in the snapshot.
+/// Files already in the journal of the snapshot, and unchanged since, are kept as they are.
+/// Up to `parallel_copies` files are copied at the same time, the entries and the failures keep the order of the files.
+#[allow(clippy::too_many_arguments)]
+fn copy_directory(
+    device_path: &Path,
+    snapshot_name: &str,
+    files: &[SourceFile],
+    cipher: Option<&Cipher>,
+    parallel_copies: usize,
+    journal: &mut Journal,
+    report: &mut BackupReport,
+    sources: &Sources,
+    progress: &Progress,
+) -> Vec<SnapshotEntry> {
+    let snapshot_path = partial_path(device_path, snapshot_name);
+    let mut manifest = Manifest::load(device_path, cipher);
+    let mut seen = HashSet::new();
+    let mut entries = vec![None; files.len()];
+    let mut copies = Vec::new();
+    let completed = read_journal::<SnapshotEntry>(&snapshot_path, cipher)
+        .unwrap_or_default()
+        .into_iter()
+        .map(|entry| (entry.get_source(), entry))
+        .collect::<HashMap<String, SnapshotEntry>>();
+
+    // The files left out of a partial snapshot still exist, their last copy stays in the manifest.
+    for left_out in report.get_left_out() {
+        seen.insert(left_out.get_path().to_string_lossy().to_string());
+    }
+
+    for (i, file) in files.iter().enumerate() {
+        // The manifest is only saved once every file has been seen, otherwise the remaining ones would look deleted.
+        if progress.is_cancelled() {
+            return entries.into_iter().flatten().collect();
+        }
+
+        let source = file.get_source().to_string_lossy().to_string();
+        let relative_path = match cipher {
+            Some(cipher) => match cipher.encrypt_path(&file.get_relative_path()) {
+                Ok(relative_path) => relative_path,
+                Err(err) => {
+                    report.add_failure(&file.get_source(), format!("{:?}", err));
+                    progress.skip_file(file.get_size());
+                    continue;
+                }
+            },
+            None => file.get_relative_path(),
+        };
+        let dst = snapshot_path.join(&relative_path);
+        let relative_path = relative_path.to_string_lossy().to_string();
+
+        seen.insert(source.clone());
+
+        if let Some(entry) = completed.get(&source).filter(|entry| {
+            entry.get_size() == file.get_size() && entry.get_modified() == file.get_modified()
+        }) {
+            entries[i] = Some(entry.clone());
+            manifest.update_entry(
+                source,
+                ManifestEntry::new(
+                    snapshot_name.to_string(),
+                    relative_path,
+                    file.get_size(),
+                    file.get_modified(),
+                    entry.get_hash(),
+                ),
+            );
+            progress.skip_file(file.get_size());
+
+            continue;
+        }
+
+        if manifest.is_unchanged(&source, file.get_size(), file.get_modified()) {
+            let entry = manifest.get_entry(&source).unwrap().clone();
+            let previous = device_path
+                .join(entry.get_snapshot())
+                .join(entry.get_path());
+
+            if link_previous(&previous, &dst).is_ok() {
+                let snapshot_entry = SnapshotEntry::from_source_file(file, entry.get_hash());
+
+                journal.record(&snapshot_entry, &file.get_source(), report);
+                entries[i] = Some(snapshot_entry);
+                manifest.update_entry(
+                    source,
+                    entry.with_snapshot(snapshot_name.to_string(), relative_path),
+                );
+                progress.skip_file(file.get_size());
+
+                continue;
+            }
+        }
+
+        copies.push((i, file, dst, source, relative_path));
+    }
+
+    let shared_journal = Mutex::new(&mut *journal);
+    let results = run_parallel(
+        &copies,
+        parallel_copies,
+        progress,
+        |(_, file, _, _, _)| file.get_size(),
+        |(_, file, dst, _, _)| {
+            progress.start_file(&file.get_source());
+
+            let result = copy_file_hashed(&file.get_source(), dst, cipher, sources, progress);
+            let recorded = match result.as_ref() {
+                Ok(hash) => shared_journal
+                    .lock()
+                    .unwrap()
+                    .append(&SnapshotEntry::from_source_file(file, hash.clone())),
+                // The file being copied when the backup is stopped is rolled back.
+                Err(_) if progress.should_stop() => std::fs::remove_file(dst),
+                Err(_) => Ok(()),
+            };
+
+            progress.finish_file();
+
+            (result, recorded)
+        },
+    );
+
+    for ((i, file, _, source, relative_path), result) in copies.into_iter().zip(results) {
+        match result {
+            Some((Ok(hash), recorded)) => {
+                if let Err(err) = recorded {
+                    report.add_failure(
+                        &file.get_source(),
+                        format!("not recorded in the journal, {}", err),
+                    );
+                }
+
+                report.add_copied_bytes(file.get_size());
+                entries[i] = Some(SnapshotEntry::from_source_file(file, hash.clone()));
+                manifest.update_entry(
+                    source,
+                    ManifestEntry::new(
+                        snapshot_name.to_string(),
+                        relative_path,
+                        file.get_size(),
+                        file.get_modified(),
+                        hash,
+                    ),
+                );
+            }
+            Some((Err(err), _)) if !progress.should_stop() => {
+                report.add_failure(&file.get_source(), err.to_string())
+            }
+            _ => {}
+        }
+    }
+
+    let entries = entries.into_iter().flatten().collect();
+
+    if progress.is_cancelled() {
+        return entries;
+    }
+
+    manifest.record_deletions(&seen, snapshot_name);
+
+    if let Err(err) = manifest.save(device_path, cipher) {
+        report.add_failure(&device_path.join(MANIFEST_FILE_NAME), err.to_string());
+    }
+
+    entries
+}
+
+/// Function to copy the files of a snapshot written as a directory tree inside the target folder.
+/// File names and contents are decrypted if a cipher is given, the metadata files of the snapshot are skipped.
+pub fn extract_directory(
+    snapshot_path: &Path,
+    target: &Path,
+    cipher: Option<&Cipher>,
+) -> std::io::Result<()> {
+    let mut files = Vec::new();
+
+    scan_dir(
+        snapshot_path,
+        Path::new(""),
+        None,
+        &mut files,
+        &mut Vec::new(),
+    );
+
+    for file in files.iter().filter(|file| {
+        !file
+            .get_relative_path()
+            .to_string_lossy()
+            .starts_with(".eb-rs")
+    }) {
+        let relative_path = match cipher {
+            Some(cipher) => cipher
+                .decrypt_path(&file.get_relative_path())
+                .map_err(|_| {
+                    std::io::Error::new(std::io::ErrorKind::InvalidData, "Corrupted file name")
+                })?,
+            None => file.get_relative_path(),
+        };
+        let dst = target.join(relative_path);
+
+        std::fs::create_dir_all(dst.parent().unwrap())?;
+
+        let mut src_file = File::open(file.get_source())?;
+        let mut dst_file = File::create(&dst)?;
+
+        match cipher {
+            Some(cipher) => copy_hashed(&mut cipher.decrypt_reader(&mut src_file)?, &mut dst_file)?,
+            None => copy_hashed(&mut src_file, &mut dst_file)?,
+        };
+
+        if let Ok(modified) = src_file.metadata().and_then(|metadata| metadata.modified()) {
+            let _ = dst_file.set_modified(modified);
+        }
+    }
+
+    Ok(())
+}
+
+/// Function to read the files of a snapshot written as a directory tree, one at a time.
+/// The callback is called with the path of every file inside the snapshot, its modification time and a reader of its
+/// content. File names and contents are decrypted if a cipher is given, the metadata files of the snapshot are skipped.
+pub fn read_directory<F: FnMut(&str, u64, std::io::Result<&mut dyn Read>)>(
+    snapshot_path: &Path,
+    cipher: Option<&Cipher>,
+    mut on_file: F,
+) -> std::io::Result<()> {
+    let mut files = Vec::new();
+
+    scan_dir(
+        snapshot_path,
+        Path::new(""),
+        None,
+        &mut files,
+        &mut Vec::new(),
+    );
+
+    for file in files.iter().filter(|file| {
+        !file
+            .get_relative_path()
+            .to_string_lossy()
+            .starts_with(".eb-rs")
+    }) {
+        let relative_path = match cipher {
+            Some(cipher) => cipher
+                .decrypt_path(&file.get_relative_path())
+                .map_err(|_| {
+                    std::io::Error::new(std::io::ErrorKind::InvalidData, "Corrupted file name")
+                })?,
+            None => file.get_relative_path(),
+        };
+        let path = relative_path.to_string_lossy();
+
+        match (File::open(file.get_source()), cipher) {
+            (Ok(src_file), Some(cipher)) => match cipher.decrypt_reader(src_file) {
+                Ok(mut reader) => on_file(&path, file.get_modified(), Ok(&mut reader)),
+                Err(err) => on_file(&path, file.get_modified(), Err(err)),
+            },
+            (Ok(mut src_file), None) => on_file(&path, file.get_modified(), Ok(&mut src_file)),
+            (Err(err), _) => on_file(&path, file.get_modified(), Err(err)),
+        }
+    }
+
+    Ok(())
+}
+
+/// Function to recognize the format of a snapshot from its content.
+pub fn snapshot_format(snapshot_path: &Path) -> BackupFormat {
+    if snapshot_path.join(INDEX_FILE_NAME).exists() {
+        BackupFormat::Repository
+    } else if snapshot_path.join(ARCHIVE_FILE_NAME).exists()
+        || snapshot_path.join(ENCRYPTED_ARCHIVE_FILE_NAME).exists()
+    {
+        BackupFormat::Archive
+    } else {
+        BackupFormat::Directory
+    }
+}
+
+/// Function to write a decrypted copy of a snapshot inside the target folder, the files are written as a plain directory tree.
+/// The format of the snapshot is recognized from its content.
+pub fn decrypt_snapshot(
+    device_path: &Path,
+    snapshot_name: &str,
+    target: &Path,
+    cipher: &Cipher,
+) -> std::io::Result<()> {
+    let snapshot_path = device_path.join(snapshot_name);
+
+    match snapshot_format(&snapshot_path) {
+        BackupFormat::Repository => {
+            extract_snapshot(device_path, snapshot_name, target, Some(cipher))
+        }
+        BackupFormat::Archive => extract_archive(&snapshot_path, target, Some(cipher)),
+        BackupFormat::Directory => extract_directory(&snapshot_path, target, Some(cipher)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_device_gets_the_same_snapshot_reading_each_source_once() {
+        let dir = std::env::temp_dir().join(format!("eb-rs-fanout-{}", std::process::id()));
+        let devices = ["FIRST", "SECOND"].map(|name| (name.to_string(), dir.join(name)));
+        let notes = dir.join("sources").join("notes.txt");
+        // A pipe can be read only once, a second reading would wait forever for a writer.
+        let pipe = dir.join("sources").join("pipe");
+        let content = b"read once ".repeat(100_000);
+
+        for (_, device_path) in devices.iter() {
+            std::fs::create_dir_all(device_path).unwrap();
+        }
+
+        std::fs::create_dir_all(dir.join("sources")).unwrap();
+        std::fs::write(&notes, b"notes").unwrap();
+        assert!(std::process::Command::new("mkfifo")
+            .arg(&pipe)
+            .status()
+            .unwrap()
+            .success());
+
+        let writer = {
+            let pipe = pipe.clone();
+            let content = content.clone();
+
+            std::thread::spawn(move || std::fs::write(pipe, content).unwrap())
+        };
+        let files = vec![
+            SourceFile::new(notes, PathBuf::from("sources/notes.txt"), 5, 0),
+            SourceFile::new(pipe, PathBuf::from("sources/pipe"), content.len() as u64, 0),
+        ];
+        let (sender, receiver) = std::sync::mpsc::channel();
+
+        {
+            let devices = devices.clone();
+
+            std::thread::spawn(move || {
+                let results = write_devices(
+                    &Config::new(String::new(), Vec::new()),
+                    &devices,
+                    &files,
+                    &[],
+                    None,
+                    false,
+                    &Progress::new(),
+                );
+
+                let _ = sender.send(results);
+            });
+        }
+
+        let results = receiver
+            .recv_timeout(Duration::from_secs(30))
+            .expect("a source has been read more than once");
+
+        writer.join().unwrap();
+
+        let snapshots = results
+            .into_iter()
+            .map(|result| {
+                let report = result.unwrap();
+                let snapshot_path = report.get_snapshot_path();
+
+                assert!(report.is_successful());
+
+                (
+                    snapshot_path.file_name().unwrap().to_os_string(),
+                    std::fs::read(snapshot_path.join("sources/notes.txt")).unwrap(),
+                    std::fs::read(snapshot_path.join("sources/pipe")).unwrap(),
+                )
+            })
+            .collect::<Vec<_>>();
+
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(snapshots.len(), 2);
+        assert_eq!(snapshots[0], snapshots[1]);
+        assert_eq!(snapshots[0].1, b"notes");
+        assert_eq!(snapshots[0].2, content);
+    }
 }
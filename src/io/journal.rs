@@ -0,0 +1,269 @@
+use crate::config::config::BackupFormat;
+use crate::io::crypto::Cipher;
+use crate::io::io::{read_metadata, write_metadata};
+use crate::io::manifest::SnapshotEntry;
+use crate::io::report::BackupReport;
+use chrono::Local;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// Name of the journal of the completed files, written inside the snapshot folder while it's written.
+pub const JOURNAL_FILE_NAME: &str = ".eb-rs-journal";
+/// Name of the marker of a snapshot still being written, or interrupted.
+pub const PARTIAL_FILE_NAME: &str = ".eb-rs-partial";
+/// Name of the completion record, written inside the snapshot folder once every file has been written.
+pub const COMPLETION_FILE_NAME: &str = ".eb-rs-complete.json";
+/// Extension added to the name of the snapshot folder until the snapshot is complete.
+pub const PARTIAL_EXTENSION: &str = "partial";
+
+/// Structure written in the marker of a partial snapshot, contains two fields.
+/// * backup_format: the format the snapshot is written in, a snapshot is only resumed in the same format.
+/// * started: the time the first attempt of the backup started at, in RFC 3339 format.
+#[derive(Debug, PartialEq, Eq, Clone, Deserialize, Serialize)]
+struct PartialMarker {
+    backup_format: BackupFormat,
+    started: String,
+}
+
+/// Structure written in the completion record of a snapshot, contains seven fields.
+/// * started: the time the backup started at, in RFC 3339 format.
+/// * finished: the time the backup has been completed at, in RFC 3339 format.
+/// * file_count: the number of files in the snapshot.
+/// * byte_count: the total size of the files in the snapshot, in bytes.
+/// * version: the version of eb-rs that wrote the snapshot.
+/// * verified: if every file has been read back from the device identical to its source.
+/// * partial: if some source files have been left out of the snapshot, the ones it holds are still consistent.
+#[derive(Debug, PartialEq, Eq, Clone, Deserialize, Serialize)]
+pub struct Completion {
+    started: String,
+    finished: String,
+    file_count: usize,
+    byte_count: u64,
+    version: String,
+    verified: bool,
+    #[serde(default)]
+    partial: bool,
+}
+
+impl Completion {
+    pub fn get_file_count(&self) -> usize {
+        self.file_count
+    }
+
+    pub fn get_byte_count(&self) -> u64 {
+        self.byte_count
+    }
+
+    pub fn is_verified(&self) -> bool {
+        self.verified
+    }
+
+    pub fn is_partial(&self) -> bool {
+        self.partial
+    }
+}
+
+/// Append-only journal of the files completed in a snapshot, one json record per line.
+/// Every record is flushed to the device before the next file is written, with a cipher the records are encrypted.
+pub struct Journal<'a> {
+    file: File,
+    cipher: Option<&'a Cipher>,
+}
+
+impl<'a> Journal<'a> {
+    /// Function to open the journal of a snapshot folder, the records are appended to the existing ones.
+    pub fn open(snapshot_path: &Path, cipher: Option<&'a Cipher>) -> std::io::Result<Journal<'a>> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(snapshot_path.join(JOURNAL_FILE_NAME))?;
+
+        Ok(Journal { file, cipher })
+    }
+
+    /// Function to append a record to the journal, it returns once the record is on the device.
+    pub fn append<T: Serialize>(&mut self, record: &T) -> std::io::Result<()> {
+        let data = serde_json::to_vec(record).unwrap();
+        let mut line = match self.cipher {
+            Some(cipher) => hex::encode(cipher.seal(&data)).into_bytes(),
+            None => data,
+        };
+
+        line.push(b'\n');
+        self.file.write_all(&line)?;
+        self.file.sync_data()
+    }
+
+    /// Function to append the record of a completed file, if it can't be written the file is reported as failed,
+    /// since an interrupted backup couldn't know it has been completed.
+    pub fn record<T: Serialize>(&mut self, record: &T, source: &Path, report: &mut BackupReport) {
+        if let Err(err) = self.append(record) {
+            report.add_failure(source, format!("not recorded in the journal, {}", err));
+        }
+    }
+}
+
+/// Function to read the records of the journal of a snapshot folder, a missing journal has no records.
+/// The last line may have been cut by an interruption and it's ignored, any other unreadable line is an error.
+pub fn read_journal<T: DeserializeOwned>(
+    snapshot_path: &Path,
+    cipher: Option<&Cipher>,
+) -> std::io::Result<Vec<T>> {
+    let data = match std::fs::read(snapshot_path.join(JOURNAL_FILE_NAME)) {
+        Ok(data) => data,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err),
+    };
+    let lines = data
+        .split(|byte| *byte == b'\n')
+        .filter(|line| !line.is_empty())
+        .collect::<Vec<&[u8]>>();
+    let mut records = Vec::new();
+
+    for (i, line) in lines.iter().enumerate() {
+        let record = match cipher {
+            Some(cipher) => hex::decode(line)
+                .ok()
+                .and_then(|sealed| cipher.open(&sealed).ok())
+                .and_then(|data| serde_json::from_slice(&data).ok()),
+            None => serde_json::from_slice(line).ok(),
+        };
+
+        match record {
+            Some(record) => records.push(record),
+            None if i == lines.len() - 1 => break,
+            None => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    "Corrupted journal",
+                ))
+            }
+        }
+    }
+
+    Ok(records)
+}
+
+/// Function to get the sources of the files completed in the journal of a partial snapshot, in any format.
+/// The records of the repository format hold the fields of a snapshot entry, the archive format records whole volumes.
+pub fn journal_sources(
+    snapshot_path: &Path,
+    backup_format: BackupFormat,
+    cipher: Option<&Cipher>,
+) -> HashSet<String> {
+    let entries = match backup_format {
+        BackupFormat::Directory | BackupFormat::Repository => {
+            read_journal::<SnapshotEntry>(snapshot_path, cipher).unwrap_or_default()
+        }
+        BackupFormat::Archive => read_journal::<Vec<SnapshotEntry>>(snapshot_path, cipher)
+            .unwrap_or_default()
+            .into_iter()
+            .flatten()
+            .collect(),
+    };
+
+    entries.iter().map(|entry| entry.get_source()).collect()
+}
+
+/// Function to get the path of the folder a snapshot is written in, until it's completed.
+pub fn partial_path(device_path: &Path, snapshot_name: &str) -> PathBuf {
+    device_path.join(format!("{}.{}", snapshot_name, PARTIAL_EXTENSION))
+}
+
+/// Function to create the folder of a partial snapshot, if it doesn't exist yet.
+/// The marker records the backup format and the start time, a resumed snapshot keeps the ones of the first attempt.
+pub fn mark_partial(snapshot_path: &Path, backup_format: BackupFormat) -> std::io::Result<()> {
+    std::fs::create_dir_all(snapshot_path)?;
+
+    if snapshot_path.join(PARTIAL_FILE_NAME).exists() {
+        return Ok(());
+    }
+
+    write_metadata(
+        &snapshot_path.join(PARTIAL_FILE_NAME),
+        &PartialMarker {
+            backup_format,
+            started: Local::now().to_rfc3339(),
+        },
+        None,
+    )
+}
+
+/// Function to complete a partial snapshot, the completion record is written and the folder is renamed to its final name.
+/// The rename is atomic, so a snapshot with its final name is always complete.
+/// The journal and the marker are no longer needed and they are removed afterwards.
+/// A partial snapshot is completed the same way, its record tells that some source files have been left out.
+pub fn mark_complete(
+    device_path: &Path,
+    snapshot_name: &str,
+    file_count: usize,
+    byte_count: u64,
+    verified: bool,
+    partial: bool,
+) -> std::io::Result<()> {
+    let snapshot_path = partial_path(device_path, snapshot_name);
+    let started = read_metadata::<PartialMarker>(&snapshot_path.join(PARTIAL_FILE_NAME), None)
+        .map(|marker| marker.started)
+        .unwrap_or_default();
+    let completion = Completion {
+        started,
+        finished: Local::now().to_rfc3339(),
+        file_count,
+        byte_count,
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        verified,
+        partial,
+    };
+
+    write_metadata(&snapshot_path.join(COMPLETION_FILE_NAME), &completion, None)?;
+
+    std::fs::rename(snapshot_path, device_path.join(snapshot_name))?;
+
+    for name in [JOURNAL_FILE_NAME, PARTIAL_FILE_NAME] {
+        match std::fs::remove_file(device_path.join(snapshot_name).join(name)) {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => return Err(err),
+            _ => {}
+        }
+    }
+
+    Ok(())
+}
+
+/// Function to read the completion record of a snapshot folder, only a complete snapshot has one.
+pub fn read_completion(snapshot_path: &Path) -> Option<Completion> {
+    read_metadata(&snapshot_path.join(COMPLETION_FILE_NAME), None)
+}
+
+/// Function to get the format a partial snapshot is written in, from its marker.
+pub fn partial_format(snapshot_path: &Path) -> Option<BackupFormat> {
+    read_metadata::<PartialMarker>(&snapshot_path.join(PARTIAL_FILE_NAME), None)
+        .map(|marker| marker.backup_format)
+}
+
+/// Function to find the most recent partial snapshot of the device written in the given format, it returns its final name.
+pub fn find_partial(device_path: &Path, backup_format: BackupFormat) -> Option<String> {
+    let suffix = format!(".{}", PARTIAL_EXTENSION);
+    let mut snapshot_names = std::fs::read_dir(device_path)
+        .ok()?
+        .flatten()
+        .filter_map(|entry| {
+            entry
+                .file_name()
+                .to_string_lossy()
+                .strip_suffix(&suffix)
+                .map(|name| name.to_string())
+        })
+        .filter(|name| name.starts_with("backup-"))
+        .collect::<Vec<String>>();
+
+    snapshot_names.sort();
+
+    snapshot_names
+        .into_iter()
+        .rev()
+        .find(|name| partial_format(&partial_path(device_path, name)) == Some(backup_format))
+}
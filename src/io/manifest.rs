@@ -0,0 +1,223 @@
+use crate::io::crypto::Cipher;
+use crate::io::io::{read_metadata, write_metadata, SourceFile};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::path::Path;
+
+/// Name of the manifest file, stored in the root of the device next to the snapshots.
+pub const MANIFEST_FILE_NAME: &str = ".eb-rs-manifest.json";
+/// Name of the snapshot manifest, written inside every snapshot folder.
+pub const SNAPSHOT_MANIFEST_FILE_NAME: &str = ".eb-rs-snapshot.json";
+
+/// Structure describing the last copy of a source file, contains five fields.
+/// * snapshot: the name of the snapshot folder holding the copy.
+/// * path: the path of the copy, relative to the snapshot folder.
+/// * size: the size of the file in bytes.
+/// * modified: the modification time of the source file, in nanoseconds since the unix epoch.
+/// * hash: the BLAKE3 hash of the file content.
+#[derive(Debug, PartialEq, Eq, Clone, Deserialize, Serialize)]
+pub struct ManifestEntry {
+    snapshot: String,
+    path: String,
+    size: u64,
+    modified: u64,
+    hash: String,
+}
+
+impl ManifestEntry {
+    pub fn new(snapshot: String, path: String, size: u64, modified: u64, hash: String) -> Self {
+        ManifestEntry {
+            snapshot,
+            path,
+            size,
+            modified,
+            hash,
+        }
+    }
+
+    pub fn get_snapshot(&self) -> String {
+        self.snapshot.clone()
+    }
+
+    pub fn get_path(&self) -> String {
+        self.path.clone()
+    }
+
+    pub fn get_hash(&self) -> String {
+        self.hash.clone()
+    }
+
+    /// Function to get a copy of the entry pointing to another snapshot.
+    pub fn with_snapshot(&self, snapshot: String, path: String) -> ManifestEntry {
+        ManifestEntry {
+            snapshot,
+            path,
+            ..self.clone()
+        }
+    }
+}
+
+/// Structure recording a source file that disappeared, with the snapshot in which the deletion has been noticed.
+#[derive(Debug, PartialEq, Eq, Clone, Deserialize, Serialize)]
+pub struct DeletedEntry {
+    source: String,
+    snapshot: String,
+}
+
+/// Per-device manifest, it maps every backed up source path to its last copy on the device.
+#[derive(Debug, PartialEq, Eq, Clone, Default, Deserialize, Serialize)]
+pub struct Manifest {
+    entries: BTreeMap<String, ManifestEntry>,
+    deleted: Vec<DeletedEntry>,
+}
+
+impl Manifest {
+    /// Function to load the manifest stored in the root of the device, the manifest is encrypted if a cipher is given.
+    /// A missing or unreadable manifest gives an empty one, so that the next backup is a full copy.
+    pub fn load(device_path: &Path, cipher: Option<&Cipher>) -> Manifest {
+        read_metadata(&device_path.join(MANIFEST_FILE_NAME), cipher).unwrap_or_default()
+    }
+
+    /// Function to save the manifest in the root of the device, the manifest is encrypted if a cipher is given.
+    pub fn save(&self, device_path: &Path, cipher: Option<&Cipher>) -> std::io::Result<()> {
+        write_metadata(&device_path.join(MANIFEST_FILE_NAME), self, cipher)
+    }
+
+    pub fn get_entry(&self, source: &str) -> Option<&ManifestEntry> {
+        self.entries.get(source)
+    }
+
+    /// Function to check if a source file has the same size and modification time of its last copy.
+    pub fn is_unchanged(&self, source: &str, size: u64, modified: u64) -> bool {
+        self.entries
+            .get(source)
+            .is_some_and(|entry| entry.size == size && entry.modified == modified)
+    }
+
+    pub fn update_entry(&mut self, source: String, entry: ManifestEntry) {
+        self.entries.insert(source, entry);
+    }
+
+    /// Function to drop the entries whose source has not been seen during the last scan.
+    /// Every removed source is recorded as deleted in the given snapshot, the removed sources are returned.
+    pub fn record_deletions(&mut self, seen: &HashSet<String>, snapshot: &str) -> Vec<String> {
+        let removed = self
+            .entries
+            .keys()
+            .filter(|source| !seen.contains(*source))
+            .cloned()
+            .collect::<Vec<String>>();
+
+        for source in removed.iter() {
+            self.entries.remove(source);
+            self.deleted.push(DeletedEntry {
+                source: source.clone(),
+                snapshot: snapshot.to_string(),
+            });
+        }
+
+        removed
+    }
+}
+
+/// Structure describing a file of a snapshot, contains five fields.
+/// * source: the absolute path of the source file.
+/// * path: the path of the file inside the snapshot, before any encryption of the names.
+/// * size: the size of the file in bytes.
+/// * modified: the modification time of the source file, in nanoseconds since the unix epoch.
+/// * hash: the BLAKE3 hash of the source content, computed while it was copied.
+#[derive(Debug, PartialEq, Eq, Clone, Deserialize, Serialize)]
+pub struct SnapshotEntry {
+    source: String,
+    path: String,
+    size: u64,
+    modified: u64,
+    hash: String,
+}
+
+impl SnapshotEntry {
+    pub fn new(source: String, path: String, size: u64, modified: u64, hash: String) -> Self {
+        SnapshotEntry {
+            source,
+            path,
+            size,
+            modified,
+            hash,
+        }
+    }
+
+    /// Function to build the entry of a source file copied in a snapshot, given the hash of its content.
+    pub fn from_source_file(file: &SourceFile, hash: String) -> Self {
+        SnapshotEntry::new(
+            file.get_source().to_string_lossy().to_string(),
+            file.get_relative_path().to_string_lossy().to_string(),
+            file.get_size(),
+            file.get_modified(),
+            hash,
+        )
+    }
+
+    pub fn get_source(&self) -> String {
+        self.source.clone()
+    }
+
+    pub fn get_path(&self) -> String {
+        self.path.clone()
+    }
+
+    pub fn get_size(&self) -> u64 {
+        self.size
+    }
+
+    pub fn get_modified(&self) -> u64 {
+        self.modified
+    }
+
+    pub fn get_hash(&self) -> String {
+        self.hash.clone()
+    }
+
+    /// Function to get a copy of the entry with another path inside the snapshot.
+    pub fn with_path(&self, path: String) -> SnapshotEntry {
+        SnapshotEntry {
+            path,
+            ..self.clone()
+        }
+    }
+}
+
+/// Per-snapshot manifest, it lists every file of the snapshot with the hash of its source, contains two fields.
+/// * algorithm: the name of the hash algorithm.
+/// * entries: the files of the snapshot.
+#[derive(Debug, PartialEq, Eq, Clone, Deserialize, Serialize)]
+pub struct SnapshotManifest {
+    algorithm: String,
+    entries: Vec<SnapshotEntry>,
+}
+
+impl SnapshotManifest {
+    pub fn new(entries: Vec<SnapshotEntry>) -> Self {
+        SnapshotManifest {
+            algorithm: "blake3".to_string(),
+            entries,
+        }
+    }
+
+    /// Function to load the manifest of a snapshot folder, the manifest is encrypted if a cipher is given.
+    pub fn load(snapshot_path: &Path, cipher: Option<&Cipher>) -> Option<SnapshotManifest> {
+        read_metadata(&snapshot_path.join(SNAPSHOT_MANIFEST_FILE_NAME), cipher)
+    }
+
+    /// Function to save the manifest inside the snapshot folder, the manifest is encrypted if a cipher is given.
+    pub fn save(&self, snapshot_path: &Path, cipher: Option<&Cipher>) -> std::io::Result<()> {
+        write_metadata(
+            &snapshot_path.join(SNAPSHOT_MANIFEST_FILE_NAME),
+            self,
+            cipher,
+        )
+    }
+
+    pub fn get_entries(&self) -> Vec<SnapshotEntry> {
+        self.entries.clone()
+    }
+}
@@ -1 +1,20 @@
+pub mod archive;
+pub mod catalog;
+pub mod cancel;
+pub mod crypto;
+pub mod diff;
+pub mod fanout;
+pub mod filter;
 pub mod io;
+pub mod journal;
+pub mod manifest;
+pub mod parallel;
+pub mod plan;
+pub mod priority;
+pub mod progress;
+pub mod report;
+pub mod restore;
+pub mod repository;
+pub mod retention;
+pub mod search;
+pub mod verify;
@@ -0,0 +1,64 @@
+use crate::io::progress::Progress;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, RwLock};
+
+/// Size from which a file is copied alone, 64 MiB: big sequential writes are faster than interleaved ones on most devices.
+pub const LARGE_FILE_SIZE: u64 = 64 * 1024 * 1024;
+
+/// Function to run a job for every item on a pool of worker threads, the items are started in order.
+/// At most `workers` jobs run at the same time, the ones whose size is at least **LARGE_FILE_SIZE** run alone.
+/// It returns the results in the order of the items, so the callers can record them deterministically.
+/// Once the backup is cancelled or it has run out of time no more jobs are started, the items left have no result.
+pub fn run_parallel<T, R, S, F>(
+    items: &[T],
+    workers: usize,
+    progress: &Progress,
+    size: S,
+    job: F,
+) -> Vec<Option<R>>
+where
+    T: Sync,
+    R: Send,
+    S: Fn(&T) -> u64 + Sync,
+    F: Fn(&T) -> R + Sync,
+{
+    let next = AtomicUsize::new(0);
+    // Small files share the lock, a large file takes it exclusively.
+    let large_file = RwLock::new(());
+    let results = items
+        .iter()
+        .map(|_| Mutex::new(None))
+        .collect::<Vec<Mutex<Option<R>>>>();
+
+    std::thread::scope(|scope| {
+        for _ in 0..workers.clamp(1, items.len().max(1)) {
+            scope.spawn(|| loop {
+                if progress.should_stop() {
+                    break;
+                }
+
+                let i = next.fetch_add(1, Ordering::SeqCst);
+                let item = match items.get(i) {
+                    Some(item) => item,
+                    None => break,
+                };
+                let result = if size(item) >= LARGE_FILE_SIZE {
+                    let _guard = large_file.write().unwrap_or_else(|err| err.into_inner());
+
+                    job(item)
+                } else {
+                    let _guard = large_file.read().unwrap_or_else(|err| err.into_inner());
+
+                    job(item)
+                };
+
+                *results[i].lock().unwrap() = Some(result);
+            });
+        }
+    });
+
+    results
+        .into_iter()
+        .map(|result| result.into_inner().unwrap())
+        .collect()
+}
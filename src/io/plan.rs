@@ -0,0 +1,110 @@
+use crate::io::io::{get_available_space, read_metadata, write_metadata};
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+/// Name of the record of the write throughput of the device, in its root.
+pub const THROUGHPUT_FILE_NAME: &str = ".eb-rs-throughput.json";
+/// Name of the file written to measure the throughput of a device never used before, in its root.
+const PROBE_FILE_NAME: &str = ".eb-rs-probe";
+/// Size of the probe file, 1 MiB, it's written out of the time budget and the space of the backup.
+const PROBE_SIZE: usize = 1024 * 1024;
+/// Minimum number of bytes a backup has to copy for its throughput to be recorded, 16 MiB.
+const MIN_MEASURED_BYTES: u64 = 16 * 1024 * 1024;
+/// Space kept free on the device for the metadata of the snapshot and the overhead of the encryption, 64 MiB.
+const SPACE_RESERVE: u64 = 64 * 1024 * 1024;
+/// Fraction of the time left the copy is planned for, the rest covers the time spent on every file besides its bytes.
+const TIME_MARGIN: f64 = 0.8;
+
+/// Structure written in the throughput record of a device, contains one field.
+/// * bytes_per_second: the bytes written per second by the last backup that copied enough of them.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Deserialize, Serialize)]
+struct Throughput {
+    bytes_per_second: u64,
+}
+
+/// Function to measure the write throughput of a device writing a probe file, the file is removed afterwards.
+fn probe_throughput(device_path: &Path) -> Option<u64> {
+    let probe_path = device_path.join(PROBE_FILE_NAME);
+    // A buffer of zeros could be compressed by the file system, so it's filled with a pattern.
+    let data = (0..PROBE_SIZE)
+        .map(|i| (i % 251) as u8)
+        .collect::<Vec<u8>>();
+    let start = Instant::now();
+    let written = File::create(&probe_path).and_then(|mut probe_file| {
+        probe_file.write_all(&data)?;
+        probe_file.sync_all()
+    });
+    let elapsed = start.elapsed().as_secs_f64();
+
+    let _ = std::fs::remove_file(&probe_path);
+
+    written
+        .ok()
+        .filter(|_| elapsed > 0.0)
+        .map(|_| (PROBE_SIZE as f64 / elapsed) as u64)
+}
+
+/// Function to get the write throughput of a device, in bytes per second.
+/// The one measured by the last backup is used, a device never used before is measured with a probe file.
+pub fn device_throughput(device_path: &Path) -> Option<u64> {
+    read_metadata::<Throughput>(&device_path.join(THROUGHPUT_FILE_NAME), None)
+        .map(|throughput| throughput.bytes_per_second)
+        .filter(|bytes_per_second| *bytes_per_second > 0)
+        .or_else(|| probe_throughput(device_path))
+}
+
+/// Function to record the throughput of a backup on its device, so the next backup can plan its copy.
+/// Backups copying too few bytes are dominated by the time spent on every file and they are not recorded.
+pub fn record_throughput(device_path: &Path, copied_bytes: u64, duration: Duration) {
+    if copied_bytes < MIN_MEASURED_BYTES || duration.is_zero() {
+        return;
+    }
+
+    let _ = write_metadata(
+        &device_path.join(THROUGHPUT_FILE_NAME),
+        &Throughput {
+            bytes_per_second: (copied_bytes as f64 / duration.as_secs_f64()) as u64,
+        },
+        None,
+    );
+}
+
+/// Function to choose the files to copy within a capacity, going through them in order.
+/// A file that doesn't fit is left out and the following ones are still considered, so the smaller ones can use the
+/// capacity left. It returns for every file if it has been chosen.
+pub fn select_within(costs: &[u64], capacity: u64) -> Vec<bool> {
+    let mut left = capacity;
+
+    costs
+        .iter()
+        .map(|cost| {
+            let fits = *cost <= left;
+
+            if fits {
+                left -= cost;
+            }
+
+            fits
+        })
+        .collect()
+}
+
+/// Function to get how many bytes can be written on a device until the deadline, with the throughput of the device.
+/// The time spent measuring a device never used before is taken from the time left.
+/// It returns None if the throughput of the device can't be measured.
+pub fn time_capacity(device_path: &Path, deadline: Instant) -> Option<u64> {
+    device_throughput(device_path).map(|bytes_per_second| {
+        let time_left = deadline.saturating_duration_since(Instant::now());
+
+        (bytes_per_second as f64 * time_left.as_secs_f64() * TIME_MARGIN) as u64
+    })
+}
+
+/// Function to get how many bytes of files can be written on a device, keeping some space for the metadata.
+/// It returns None if the space available on the device can't be read.
+pub fn space_capacity(device_path: &Path) -> Option<u64> {
+    get_available_space(device_path).map(|available| available.saturating_sub(SPACE_RESERVE))
+}
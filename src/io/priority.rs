@@ -0,0 +1,29 @@
+use crate::io::io::SourceFile;
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+use std::path::Path;
+
+/// Function to get the priority of a file, the one of the most specific configured folder containing it.
+/// Files outside every folder with a priority have priority zero.
+pub fn file_priority(source: &Path, folder_priorities: &BTreeMap<String, u32>) -> u32 {
+    folder_priorities
+        .iter()
+        .filter(|(folder, _)| source.starts_with(folder))
+        .max_by_key(|(folder, _)| Path::new(folder).components().count())
+        .map(|(_, priority)| *priority)
+        .unwrap_or(0)
+}
+
+/// Function to sort the source files in the order they are copied, so an interrupted backup holds the most valuable files.
+/// Files in the folders with the highest priority come first, then the most recently modified ones, then the smallest ones.
+/// Files equal in all three are sorted by path, so the order doesn't depend on the one of the folder listing.
+pub fn sort_by_priority(files: &mut [SourceFile], folder_priorities: &BTreeMap<String, u32>) {
+    files.sort_by_cached_key(|file| {
+        (
+            Reverse(file_priority(&file.get_source(), folder_priorities)),
+            Reverse(file.get_modified()),
+            file.get_size(),
+            file.get_relative_path(),
+        )
+    });
+}
@@ -0,0 +1,325 @@
+use crate::io::cancel::CancellationToken;
+use byte_unit::{Byte, UnitType};
+use log::info;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Minimum time between two updates sent to the subscribers, the first and the last update of a phase are always sent.
+const UPDATE_INTERVAL: Duration = Duration::from_millis(100);
+/// Minimum time between two updates written in the log.
+const LOG_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Enum to handle the phases of a backup.
+/// * Scanning: the source folders are being listed.
+/// * Copying: the files are being written on the device.
+/// * Verifying: the snapshot is being read back from the device.
+/// * Finished: the backup is over, no more updates are sent.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub enum ProgressPhase {
+    #[default]
+    Scanning,
+    Copying,
+    Verifying,
+    Finished,
+}
+
+/// Structure describing the progress of a backup, contains eight fields.
+/// * phase: the current phase of the backup.
+/// * current_file: the source file being written.
+/// * bytes_done: the bytes written so far.
+/// * bytes_total: the bytes to write, files already on the device are not counted.
+/// * files_done: the files processed so far, copied, failed or already on the device.
+/// * files_total: the files to process.
+/// * throughput: the average bytes written per second.
+/// * eta: the estimated time left to write the remaining bytes, unknown until something has been written.
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct ProgressUpdate {
+    phase: ProgressPhase,
+    current_file: PathBuf,
+    bytes_done: u64,
+    bytes_total: u64,
+    files_done: usize,
+    files_total: usize,
+    throughput: f64,
+    eta: Option<Duration>,
+}
+
+impl ProgressUpdate {
+    pub fn get_phase(&self) -> ProgressPhase {
+        self.phase
+    }
+
+    pub fn get_current_file(&self) -> PathBuf {
+        self.current_file.clone()
+    }
+
+    pub fn get_bytes_done(&self) -> u64 {
+        self.bytes_done
+    }
+
+    pub fn get_bytes_total(&self) -> u64 {
+        self.bytes_total
+    }
+
+    pub fn get_files_done(&self) -> usize {
+        self.files_done
+    }
+
+    pub fn get_files_total(&self) -> usize {
+        self.files_total
+    }
+
+    pub fn get_eta(&self) -> Option<Duration> {
+        self.eta
+    }
+}
+
+impl std::fmt::Display for ProgressUpdate {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.phase {
+            ProgressPhase::Scanning => write!(f, "Scanning the source folders"),
+            ProgressPhase::Copying => {
+                write!(
+                    f,
+                    "Copying {}/{} files, {:.1} of {:.1}, {:.1}/s",
+                    self.files_done,
+                    self.files_total,
+                    Byte::from(self.bytes_done).get_appropriate_unit(UnitType::Decimal),
+                    Byte::from(self.bytes_total).get_appropriate_unit(UnitType::Decimal),
+                    Byte::from(self.throughput as u64).get_appropriate_unit(UnitType::Decimal)
+                )?;
+
+                if let Some(eta) = self.eta {
+                    write!(f, ", {}s left", eta.as_secs())?;
+                }
+
+                Ok(())
+            }
+            ProgressPhase::Verifying => write!(f, "Verifying {} files", self.files_total),
+            ProgressPhase::Finished => write!(f, "Backup finished"),
+        }
+    }
+}
+
+/// Structure shared between the copy engine and its subscribers, contains four fields.
+/// * update: the current progress.
+/// * started: when the copy phase started, the throughput is computed from it.
+/// * last_sent: when the last update has been sent.
+/// * subscribers: the channels the updates are sent to, the ones whose receiver has been dropped are removed.
+#[derive(Default)]
+struct ProgressState {
+    update: ProgressUpdate,
+    started: Option<Instant>,
+    last_sent: Option<Instant>,
+    subscribers: Vec<Sender<ProgressUpdate>>,
+}
+
+impl ProgressState {
+    /// Function to send the current progress to the subscribers, unless the last update has been sent too recently.
+    fn publish(&mut self, force: bool) {
+        let now = Instant::now();
+
+        if !force
+            && self
+                .last_sent
+                .is_some_and(|last_sent| now.duration_since(last_sent) < UPDATE_INTERVAL)
+        {
+            return;
+        }
+
+        if let Some(started) = self.started {
+            let elapsed = now.duration_since(started).as_secs_f64();
+            let update = &mut self.update;
+
+            update.throughput = if elapsed > 0.0 {
+                update.bytes_done as f64 / elapsed
+            } else {
+                0.0
+            };
+            update.eta = (update.throughput > 0.0).then(|| {
+                Duration::from_secs_f64(
+                    update.bytes_total.saturating_sub(update.bytes_done) as f64 / update.throughput,
+                )
+            });
+        }
+
+        let update = self.update.clone();
+
+        self.subscribers
+            .retain(|subscriber| subscriber.send(update.clone()).is_ok());
+        self.last_sent = Some(now);
+    }
+}
+
+/// Progress of a backup, updated by the copy engine while it writes the snapshot.
+/// It carries the cancellation token of the backup, so whoever follows the backup can stop it.
+/// It can be cloned and shared between threads, every clone refers to the same backup.
+#[derive(Clone, Default)]
+pub struct Progress {
+    state: Arc<Mutex<ProgressState>>,
+    cancellation: CancellationToken,
+}
+
+impl Progress {
+    pub fn new() -> Self {
+        Progress::default()
+    }
+
+    /// Function to get a progress of the same backup with its own counters and no subscribers, for a copy running alongside
+    /// the one the subscribers follow. It shares the cancellation and the deadline of the backup.
+    pub fn detached(&self) -> Self {
+        Progress {
+            state: Arc::default(),
+            cancellation: self.cancellation.clone(),
+        }
+    }
+
+    /// Function to subscribe to the progress of the backup, the receiver gets the updates until the backup is finished.
+    pub fn subscribe(&self) -> Receiver<ProgressUpdate> {
+        let (sender, receiver) = channel();
+        let mut state = self.state.lock().unwrap();
+
+        let _ = sender.send(state.update.clone());
+        state.subscribers.push(sender);
+
+        receiver
+    }
+
+    /// Function to start the copy phase, with the number of files and bytes of the sources.
+    pub fn start(&self, files_total: usize, bytes_total: u64) {
+        let mut state = self.state.lock().unwrap();
+
+        state.update = ProgressUpdate {
+            phase: ProgressPhase::Copying,
+            files_total,
+            bytes_total,
+            ..Default::default()
+        };
+        state.started = Some(Instant::now());
+        state.publish(true);
+    }
+
+    /// Function to set the file being written.
+    pub fn start_file(&self, path: &Path) {
+        let mut state = self.state.lock().unwrap();
+
+        state.update.current_file = path.to_path_buf();
+        state.publish(false);
+    }
+
+    /// Function to add the bytes just written.
+    pub fn add_bytes(&self, bytes: u64) {
+        let mut state = self.state.lock().unwrap();
+
+        state.update.bytes_done += bytes;
+        state.publish(false);
+    }
+
+    /// Function to count a processed file, copied or failed.
+    pub fn finish_file(&self) {
+        let mut state = self.state.lock().unwrap();
+
+        state.update.files_done += 1;
+        state.publish(false);
+    }
+
+    /// Function to count a file already on the device, its bytes are removed from the ones to write.
+    pub fn skip_file(&self, size: u64) {
+        let mut state = self.state.lock().unwrap();
+
+        state.update.files_done += 1;
+        state.update.bytes_total = state.update.bytes_total.saturating_sub(size);
+        state.publish(false);
+    }
+
+    /// Function to start the verification phase.
+    pub fn start_verification(&self) {
+        let mut state = self.state.lock().unwrap();
+
+        state.update.phase = ProgressPhase::Verifying;
+        state.update.current_file = PathBuf::new();
+        state.publish(true);
+    }
+
+    /// Function to end the backup, the last update is sent and the subscribers are dropped, so their receivers stop waiting.
+    pub fn finish(&self) {
+        let mut state = self.state.lock().unwrap();
+
+        state.update.phase = ProgressPhase::Finished;
+        state.publish(true);
+        state.subscribers.clear();
+    }
+
+    pub fn get_cancellation_token(&self) -> CancellationToken {
+        self.cancellation.clone()
+    }
+
+    /// Function to ask the copy engine to stop the backup, the snapshot is left partial so it can be resumed.
+    pub fn cancel(&self) {
+        self.cancellation.cancel();
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancellation.is_cancelled()
+    }
+
+    /// Function to set the time the copy engine has to stop at, the snapshot is completed with the files written so far.
+    pub fn set_deadline(&self, deadline: Instant) {
+        self.cancellation.set_deadline(deadline);
+    }
+
+    pub fn is_out_of_time(&self) -> bool {
+        self.cancellation.is_out_of_time()
+    }
+
+    /// Function to check if the copy engine has to stop, because the backup has been cancelled or it has run out of time.
+    pub fn should_stop(&self) -> bool {
+        self.cancellation.should_stop()
+    }
+
+    /// Function to wrap a reader, the bytes read through it are added to the progress.
+    pub fn reader<R: Read>(&self, inner: R) -> ProgressReader<'_, R> {
+        ProgressReader {
+            inner,
+            progress: self,
+        }
+    }
+}
+
+/// Reader adding the bytes read through it to the progress of the backup.
+pub struct ProgressReader<'a, R: Read> {
+    inner: R,
+    progress: &'a Progress,
+}
+
+impl<R: Read> Read for ProgressReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.inner.read(buf)?;
+
+        self.progress.add_bytes(read as u64);
+
+        Ok(read)
+    }
+}
+
+/// Function to write the updates of a backup in the log, at most one every ten seconds besides the phase changes.
+/// It returns once the backup is finished.
+pub fn log_progress(updates: Receiver<ProgressUpdate>) {
+    let mut last_logged: Option<(Instant, ProgressPhase)> = None;
+
+    for update in updates {
+        let now = Instant::now();
+
+        if last_logged.is_some_and(|(time, phase)| {
+            phase == update.phase && now.duration_since(time) < LOG_INTERVAL
+        }) {
+            continue;
+        }
+
+        info!("{}", update);
+        last_logged = Some((now, update.phase));
+    }
+}
@@ -0,0 +1,340 @@
+use crate::io::crypto::CryptoError;
+use crate::io::verify::VerificationReport;
+use byte_unit::{Byte, UnitType};
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Enum to handle the errors that stop a backup before its snapshot is complete.
+/// * DeviceNotFound: the configured device is not connected.
+/// * DeviceDisconnected: the device has been disconnected during the backup, the snapshot is left partial.
+/// * EncryptionError: the encryption key can't be loaded or created.
+/// * SnapshotError: the snapshot can't be written, the message describes why.
+/// * NothingToResume: there is no interrupted backup in the configured format.
+/// * SnapshotNotFound: the snapshot to restore is not on the device.
+/// * TargetNeeded: the snapshot to restore has no manifest, so the original paths of its files are unknown.
+/// * SnapshotUnreadable: the files of a snapshot can't be listed, the message describes why.
+/// * InvalidPattern: the search pattern is not a valid glob, the message describes why.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum BackupError {
+    DeviceNotFound,
+    DeviceDisconnected,
+    EncryptionError(CryptoError),
+    SnapshotError(String),
+    NothingToResume,
+    SnapshotNotFound(String),
+    TargetNeeded,
+    SnapshotUnreadable(String),
+    InvalidPattern(String),
+}
+
+impl std::fmt::Display for BackupError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BackupError::DeviceNotFound => write!(f, "The device is not connected"),
+            BackupError::DeviceDisconnected => {
+                write!(f, "The device has been disconnected during the backup")
+            }
+            BackupError::EncryptionError(err) => {
+                write!(f, "Unable to load the encryption key: {:?}", err)
+            }
+            BackupError::SnapshotError(message) => {
+                write!(f, "Unable to write the snapshot: {}", message)
+            }
+            BackupError::NothingToResume => write!(f, "There is no interrupted backup to resume"),
+            BackupError::SnapshotNotFound(name) => {
+                write!(f, "The snapshot {} is not on the device", name)
+            }
+            BackupError::TargetNeeded => write!(
+                f,
+                "The snapshot has no manifest, a target folder is needed to restore it"
+            ),
+            BackupError::SnapshotUnreadable(message) => {
+                write!(f, "Unable to read the snapshot: {}", message)
+            }
+            BackupError::InvalidPattern(message) => {
+                write!(f, "Invalid search pattern: {}", message)
+            }
+        }
+    }
+}
+
+impl From<std::io::Error> for BackupError {
+    fn from(err: std::io::Error) -> Self {
+        BackupError::SnapshotError(err.to_string())
+    }
+}
+
+/// Structure describing a file or a folder left out of the snapshot, contains two fields.
+/// * path: the path of the file or the folder.
+/// * reason: why it has been left out.
+#[derive(Debug, PartialEq, Eq, Clone, Serialize)]
+pub struct BackupIssue {
+    path: PathBuf,
+    reason: String,
+}
+
+impl BackupIssue {
+    pub fn new(path: &Path, reason: String) -> Self {
+        BackupIssue {
+            path: path.to_path_buf(),
+            reason,
+        }
+    }
+
+    pub fn get_path(&self) -> PathBuf {
+        self.path.clone()
+    }
+}
+
+impl std::fmt::Display for BackupIssue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.path.display(), self.reason)
+    }
+}
+
+/// Structure describing what a backup did, contains thirteen fields.
+/// * snapshot_path: the path of the snapshot folder on the device.
+/// * resumed: if the backup continued an interrupted one.
+/// * cancelled: if the backup has been cancelled, the snapshot is left partial and the next backup resumes it.
+/// * file_count: the number of files in the snapshot.
+/// * byte_count: the total size of the files in the snapshot, in bytes.
+/// * copied_bytes: the bytes read from the sources and written on the device, unchanged files are not counted.
+/// * duration: the time the backup took.
+/// * failures: the files that couldn't be copied, with the reason.
+/// * skipped: the files and folders skipped while scanning the sources, with the reason.
+/// * left_out: the files left out of a partial snapshot, with the reason, the snapshot is consistent without them.
+/// * out_of_time: if the time budget has run out, the snapshot is not verified.
+/// * removed_snapshots: the old snapshots removed to make room for the new one.
+/// * verification: the result of reading back the snapshot.
+#[derive(Debug, PartialEq, Eq, Clone, Default)]
+pub struct BackupReport {
+    snapshot_path: PathBuf,
+    resumed: bool,
+    cancelled: bool,
+    file_count: usize,
+    byte_count: u64,
+    copied_bytes: u64,
+    duration: Duration,
+    failures: Vec<BackupIssue>,
+    skipped: Vec<BackupIssue>,
+    left_out: Vec<BackupIssue>,
+    out_of_time: bool,
+    removed_snapshots: Vec<String>,
+    verification: VerificationReport,
+}
+
+impl BackupReport {
+    pub fn new(snapshot_path: PathBuf, resumed: bool) -> Self {
+        BackupReport {
+            snapshot_path,
+            resumed,
+            ..Default::default()
+        }
+    }
+
+    pub fn get_snapshot_path(&self) -> PathBuf {
+        self.snapshot_path.clone()
+    }
+
+    pub fn get_file_count(&self) -> usize {
+        self.file_count
+    }
+
+    pub fn get_byte_count(&self) -> u64 {
+        self.byte_count
+    }
+
+    pub fn get_copied_bytes(&self) -> u64 {
+        self.copied_bytes
+    }
+
+    pub fn get_duration(&self) -> Duration {
+        self.duration
+    }
+
+    pub fn get_failures(&self) -> Vec<BackupIssue> {
+        self.failures.clone()
+    }
+
+    pub fn get_skipped(&self) -> Vec<BackupIssue> {
+        self.skipped.clone()
+    }
+
+    pub fn get_left_out(&self) -> Vec<BackupIssue> {
+        self.left_out.clone()
+    }
+
+    pub fn get_verification(&self) -> VerificationReport {
+        self.verification.clone()
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled
+    }
+
+    /// Function to check if the snapshot misses some of the source files, on purpose.
+    pub fn is_partial(&self) -> bool {
+        !self.left_out.is_empty()
+    }
+
+    pub fn is_out_of_time(&self) -> bool {
+        self.out_of_time
+    }
+
+    /// Function to check if every file has been copied and read back identical to its source.
+    pub fn is_successful(&self) -> bool {
+        !self.cancelled
+            && !self.out_of_time
+            && self.failures.is_empty()
+            && self.left_out.is_empty()
+            && self.verification.is_passed()
+    }
+
+    /// Function to record that the backup has been cancelled, the snapshot is left in its partial folder.
+    pub fn set_cancelled(&mut self, snapshot_path: PathBuf) {
+        self.cancelled = true;
+        self.snapshot_path = snapshot_path;
+    }
+
+    pub fn set_totals(&mut self, file_count: usize, byte_count: u64) {
+        self.file_count = file_count;
+        self.byte_count = byte_count;
+    }
+
+    pub fn set_duration(&mut self, duration: Duration) {
+        self.duration = duration;
+    }
+
+    pub fn set_verification(&mut self, verification: VerificationReport) {
+        self.verification = verification;
+    }
+
+    pub fn add_copied_bytes(&mut self, bytes: u64) {
+        self.copied_bytes += bytes;
+    }
+
+    pub fn add_failure(&mut self, path: &Path, reason: String) {
+        self.failures.push(BackupIssue::new(path, reason));
+    }
+
+    pub fn set_skipped(&mut self, skipped: Vec<BackupIssue>) {
+        self.skipped = skipped;
+    }
+
+    pub fn add_left_out(&mut self, path: &Path, reason: String) {
+        self.left_out.push(BackupIssue::new(path, reason));
+    }
+
+    /// Function to record that the time budget has run out, the snapshot is completed without being verified.
+    pub fn set_out_of_time(&mut self) {
+        self.out_of_time = true;
+    }
+
+    pub fn add_removed_snapshot(&mut self, name: String) {
+        self.removed_snapshots.push(name);
+    }
+}
+
+impl std::fmt::Display for BackupReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "Backup {} in {:.1}s: {}",
+            if self.cancelled {
+                "cancelled"
+            } else if self.resumed {
+                "resumed"
+            } else {
+                "completed"
+            },
+            self.duration.as_secs_f64(),
+            self.snapshot_path.display()
+        )?;
+        writeln!(
+            f,
+            "{} files, {:.1}, {:.1} copied",
+            self.file_count,
+            Byte::from(self.byte_count).get_appropriate_unit(UnitType::Decimal),
+            Byte::from(self.copied_bytes).get_appropriate_unit(UnitType::Decimal)
+        )?;
+
+        for name in self.removed_snapshots.iter() {
+            writeln!(f, "Removed old snapshot {}", name)?;
+        }
+
+        for failure in self.failures.iter() {
+            writeln!(f, "Failed {}", failure)?;
+        }
+
+        for skipped in self.skipped.iter() {
+            writeln!(f, "Skipped {}", skipped)?;
+        }
+
+        for left_out in self.left_out.iter() {
+            writeln!(f, "Left out {}", left_out)?;
+        }
+
+        // A cancelled snapshot is not verified.
+        if self.cancelled {
+            return write!(f, "The snapshot is incomplete, the next backup resumes it");
+        }
+
+        if self.is_partial() {
+            writeln!(
+                f,
+                "The snapshot is partial, {} files have been left out",
+                self.left_out.len()
+            )?;
+        }
+
+        if self.out_of_time {
+            return write!(f, "Verification skipped, the time budget has run out");
+        }
+
+        write!(f, "{}", self.verification)
+    }
+}
+
+/// Structure describing a backup written to every configured device, contains one field.
+/// * results: the name of each configured device with the result of the backup on it, in the configured order.
+#[derive(Debug, PartialEq, Eq, Clone, Default)]
+pub struct DevicesReport {
+    results: Vec<(String, Result<BackupReport, BackupError>)>,
+}
+
+impl DevicesReport {
+    pub fn get_results(&self) -> Vec<(String, Result<BackupReport, BackupError>)> {
+        self.results.clone()
+    }
+
+    /// Function to check if the backup succeeded on at least one device, the others may be missing or have failed.
+    pub fn is_successful(&self) -> bool {
+        self.results
+            .iter()
+            .any(|(_, result)| result.as_ref().is_ok_and(|report| report.is_successful()))
+    }
+
+    pub fn add_result(&mut self, device_name: String, result: Result<BackupReport, BackupError>) {
+        self.results.push((device_name, result));
+    }
+}
+
+impl std::fmt::Display for DevicesReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (i, (device_name, result)) in self.results.iter().enumerate() {
+            if i > 0 {
+                write!(f, "\n\n")?;
+            }
+
+            writeln!(f, "Device {}", device_name)?;
+
+            match result {
+                Ok(report) => write!(f, "{}", report)?,
+                Err(err) => write!(f, "Backup failed: {}", err)?,
+            }
+        }
+
+        Ok(())
+    }
+}
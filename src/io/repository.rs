@@ -0,0 +1,603 @@
+use crate::io::crypto::Cipher;
+use crate::io::fanout::Sources;
+use crate::io::io::{read_metadata, write_metadata, SourceFile};
+use crate::io::journal::{read_journal, Journal};
+use crate::io::manifest::SnapshotEntry;
+use crate::io::parallel::run_parallel;
+use crate::io::progress::Progress;
+use crate::io::report::BackupReport;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Cursor, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, UNIX_EPOCH};
+
+/// Name of the folder, in the root of the device, where the chunks of every snapshot are stored.
+pub const REPOSITORY_DIR_NAME: &str = ".eb-rs-repository";
+/// Name of the index file written inside the snapshot folder.
+pub const INDEX_FILE_NAME: &str = ".eb-rs-index.json";
+
+/// Chunks are never smaller than 256 KiB, except the last one of a file.
+const MIN_CHUNK_SIZE: usize = 256 * 1024;
+/// Chunks are never bigger than 4 MiB.
+const MAX_CHUNK_SIZE: usize = 4 * 1024 * 1024;
+/// A cut point is found on average every 1 MiB after the minimum size.
+const CHUNK_MASK: u64 = (1 << 20) - 1;
+
+/// Random table for the gear rolling hash, generated at compile time with splitmix64.
+const GEAR: [u64; 256] = {
+    let mut table = [0u64; 256];
+    let mut state: u64 = 0x9E37_79B9_7F4A_7C15;
+    let mut i = 0;
+
+    while i < 256 {
+        state = state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+
+    table
+};
+
+/// Structure describing a file of a snapshot stored in the repository, contains two fields.
+/// * file: the source, path, size, modification time and hash of the file.
+/// * chunks: the BLAKE3 hashes of the chunks, in order, the file is their concatenation.
+#[derive(Debug, PartialEq, Eq, Clone, Deserialize, Serialize)]
+pub struct IndexEntry {
+    #[serde(flatten)]
+    file: SnapshotEntry,
+    chunks: Vec<String>,
+}
+
+/// Per-snapshot index, it lists the files of the snapshot and the chunks they are made of.
+#[derive(Debug, PartialEq, Eq, Clone, Default, Deserialize, Serialize)]
+pub struct SnapshotIndex {
+    entries: Vec<IndexEntry>,
+}
+
+impl SnapshotIndex {
+    /// Function to load the index of a snapshot folder, the index is encrypted if a cipher is given.
+    pub fn load(snapshot_path: &Path, cipher: Option<&Cipher>) -> Option<SnapshotIndex> {
+        read_metadata(&snapshot_path.join(INDEX_FILE_NAME), cipher)
+    }
+
+    /// Function to save the index inside the snapshot folder, the index is encrypted if a cipher is given.
+    pub fn save(&self, snapshot_path: &Path, cipher: Option<&Cipher>) -> std::io::Result<()> {
+        write_metadata(&snapshot_path.join(INDEX_FILE_NAME), self, cipher)
+    }
+}
+
+/// Function to split the content of a reader in content-defined chunks, using a gear rolling hash.
+/// The callback is called with every chunk in order, the same content always gives the same cut points,
+/// so an insertion in a big file only changes the chunks around it.
+pub fn split_chunks<R: Read, F: FnMut(&[u8]) -> std::io::Result<()>>(
+    mut reader: R,
+    mut on_chunk: F,
+) -> std::io::Result<()> {
+    let mut buffer = vec![0u8; MAX_CHUNK_SIZE];
+    let mut filled = 0;
+    let mut eof = false;
+
+    loop {
+        while !eof && filled < MAX_CHUNK_SIZE {
+            let read = reader.read(&mut buffer[filled..])?;
+
+            if read == 0 {
+                eof = true;
+            }
+
+            filled += read;
+        }
+
+        if filled == 0 {
+            return Ok(());
+        }
+
+        let cut = find_cut_point(&buffer[..filled]);
+
+        on_chunk(&buffer[..cut])?;
+        buffer.copy_within(cut..filled, 0);
+        filled -= cut;
+    }
+}
+
+/// Function to find the end of the first chunk of the data.
+fn find_cut_point(data: &[u8]) -> usize {
+    if data.len() <= MIN_CHUNK_SIZE {
+        return data.len();
+    }
+
+    let mut fingerprint: u64 = 0;
+
+    for (i, byte) in data.iter().enumerate().skip(MIN_CHUNK_SIZE) {
+        fingerprint = (fingerprint << 1).wrapping_add(GEAR[*byte as usize]);
+
+        if fingerprint & CHUNK_MASK == 0 {
+            return i + 1;
+        }
+    }
+
+    data.len()
+}
+
+/// Function to get the path of a chunk inside the repository, chunks are spread in folders named after the first two hex digits.
+fn chunk_path(repository_path: &Path, hash: &str) -> PathBuf {
+    repository_path.join("chunks").join(&hash[..2]).join(hash)
+}
+
+/// Counter making the names of the temporary chunk files unique.
+static TMP_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// Function to store a chunk in the repository, if a chunk with the same hash exists it's not written again.
+/// It returns the number of bytes written on the device.
+fn store_chunk(repository_path: &Path, hash: &str, data: &[u8]) -> std::io::Result<u64> {
+    let path = chunk_path(repository_path, hash);
+
+    if path.exists() {
+        return Ok(0);
+    }
+
+    std::fs::create_dir_all(path.parent().unwrap())?;
+
+    // Two files may store the same chunk at the same time, so every writer has its own temporary file.
+    let tmp_path = path.with_extension(format!(
+        "{}.tmp",
+        TMP_COUNTER.fetch_add(1, Ordering::SeqCst)
+    ));
+    let mut chunk_file = File::create(&tmp_path)?;
+
+    chunk_file.write_all(data)?;
+    chunk_file.sync_all()?;
+    std::fs::rename(tmp_path, path)?;
+
+    Ok(data.len() as u64)
+}
+
+/// Function to chunk a file and store the missing chunks in the repository.
+/// With a cipher the chunks are encrypted and identified by a keyed hash, so the identifiers don't reveal the content.
+fn store_file(
+    repository_path: &Path,
+    file: &SourceFile,
+    cipher: Option<&Cipher>,
+    sources: &Sources,
+    progress: &Progress,
+) -> std::io::Result<IndexEntry> {
+    let mut hasher = blake3::Hasher::new();
+    let mut chunks = Vec::new();
+
+    let reader = progress
+        .get_cancellation_token()
+        .reader(progress.reader(sources.open(&file.get_source())?));
+
+    split_chunks(reader, |chunk| {
+        hasher.update(chunk);
+
+        let chunk_hash = match cipher {
+            Some(cipher) => {
+                let chunk_hash = cipher.keyed_hash(chunk);
+
+                if !chunk_path(repository_path, &chunk_hash).exists() {
+                    store_chunk(repository_path, &chunk_hash, &cipher.seal(chunk))?;
+                }
+
+                chunk_hash
+            }
+            None => {
+                let chunk_hash = blake3::hash(chunk).to_hex().to_string();
+
+                store_chunk(repository_path, &chunk_hash, chunk)?;
+
+                chunk_hash
+            }
+        };
+
+        chunks.push(chunk_hash);
+
+        Ok(())
+    })?;
+
+    Ok(IndexEntry {
+        file: SnapshotEntry::from_source_file(file, hasher.finalize().to_hex().to_string()),
+        chunks,
+    })
+}
+
+/// Function to list the chunks used by the files of a snapshot, a snapshot not stored in the repository uses none.
+/// The chunks of a partial snapshot are read from its journal, so they are kept until it's resumed.
+/// An index or a journal that exists but can't be read is an error, so its chunks are never considered unused.
+pub fn snapshot_chunks(
+    snapshot_path: &Path,
+    cipher: Option<&Cipher>,
+) -> std::io::Result<Vec<String>> {
+    if !snapshot_path.join(INDEX_FILE_NAME).exists() {
+        return Ok(read_journal::<IndexEntry>(snapshot_path, cipher)?
+            .into_iter()
+            .flat_map(|entry| entry.chunks)
+            .collect());
+    }
+
+    let index = SnapshotIndex::load(snapshot_path, cipher).ok_or(std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        "Unable to read the index",
+    ))?;
+
+    Ok(index
+        .entries
+        .into_iter()
+        .flat_map(|entry| entry.chunks)
+        .collect())
+}
+
+/// Function to remove a chunk no longer used by any snapshot from the repository.
+pub fn remove_chunk(device_path: &Path, hash: &str) -> std::io::Result<()> {
+    std::fs::remove_file(chunk_path(&device_path.join(REPOSITORY_DIR_NAME), hash))
+}
+
+/// Function to find the index of the most recent snapshot on the device written in the repository format.
+fn latest_index(
+    device_path: &Path,
+    snapshot_path: &Path,
+    cipher: Option<&Cipher>,
+) -> Option<SnapshotIndex> {
+    let mut snapshot_names = std::fs::read_dir(device_path)
+        .ok()?
+        .flatten()
+        .map(|entry| entry.file_name().to_string_lossy().to_string())
+        .filter(|name| name.starts_with("backup-") && device_path.join(name) != snapshot_path)
+        .collect::<Vec<String>>();
+
+    snapshot_names.sort();
+
+    snapshot_names
+        .iter()
+        .rev()
+        .find_map(|name| SnapshotIndex::load(&device_path.join(name), cipher))
+}
+
+/// Function to get the files of the most recent snapshot on the device written in the repository format.
+pub fn previous_files(
+    device_path: &Path,
+    snapshot_path: &Path,
+    cipher: Option<&Cipher>,
+) -> Vec<SnapshotEntry> {
+    latest_index(device_path, snapshot_path, cipher)
+        .map(|index| index.entries.into_iter().map(|entry| entry.file).collect())
+        .unwrap_or_default()
+}
+
+/// Function to store a snapshot in the deduplicated repository of the device.
+/// Files are split in content-defined chunks stored once by hash, the snapshot folder only holds the index describing them.
+/// Files with the same size and modification time of the previous snapshot reuse its chunk list without being read again.
+/// With a cipher both the chunks and the index are encrypted. It returns the files stored in the snapshot, an index that can't be saved is an error.
+/// Files already in the journal of the snapshot, and unchanged since, reuse the chunk list recorded there.
+/// Up to `parallel_copies` files are stored at the same time, the index and the failures keep the order of the files.
+#[allow(clippy::too_many_arguments)]
+pub fn store_snapshot(
+    device_path: &Path,
+    snapshot_path: &Path,
+    files: &[SourceFile],
+    cipher: Option<&Cipher>,
+    parallel_copies: usize,
+    journal: &mut Journal,
+    report: &mut BackupReport,
+    sources: &Sources,
+    progress: &Progress,
+) -> std::io::Result<Vec<SnapshotEntry>> {
+    let repository_path = device_path.join(REPOSITORY_DIR_NAME);
+    let mut previous = latest_index(device_path, snapshot_path, cipher)
+        .map(|index| index.entries)
+        .unwrap_or_default()
+        .into_iter()
+        .map(|entry| (entry.file.get_source(), entry))
+        .collect::<HashMap<String, IndexEntry>>();
+
+    for entry in read_journal::<IndexEntry>(snapshot_path, cipher).unwrap_or_default() {
+        previous.insert(entry.file.get_source(), entry);
+    }
+    let mut entries = vec![None; files.len()];
+    let mut stores = Vec::new();
+
+    for (i, file) in files.iter().enumerate() {
+        let source = file.get_source().to_string_lossy().to_string();
+
+        if let Some(entry) = previous.get(&source).filter(|entry| {
+            entry.file.get_size() == file.get_size()
+                && entry.file.get_modified() == file.get_modified()
+        }) {
+            entries[i] = Some(IndexEntry {
+                file: entry
+                    .file
+                    .with_path(file.get_relative_path().to_string_lossy().to_string()),
+                chunks: entry.chunks.clone(),
+            });
+            progress.skip_file(file.get_size());
+
+            continue;
+        }
+
+        stores.push((i, file));
+    }
+
+    let shared_journal = Mutex::new(&mut *journal);
+    let results = run_parallel(
+        &stores,
+        parallel_copies,
+        progress,
+        |(_, file)| file.get_size(),
+        |(_, file)| {
+            progress.start_file(&file.get_source());
+
+            let result = store_file(&repository_path, file, cipher, sources, progress);
+            let recorded = match result.as_ref() {
+                Ok(entry) => shared_journal.lock().unwrap().append(entry),
+                Err(_) => Ok(()),
+            };
+
+            progress.finish_file();
+
+            (result, recorded)
+        },
+    );
+
+    for ((i, file), result) in stores.into_iter().zip(results) {
+        match result {
+            Some((Ok(entry), recorded)) => {
+                if let Err(err) = recorded {
+                    report.add_failure(
+                        &file.get_source(),
+                        format!("not recorded in the journal, {}", err),
+                    );
+                }
+
+                report.add_copied_bytes(file.get_size());
+                entries[i] = Some(entry);
+            }
+            // The chunks of the file being stored when the backup is stopped are reused by the next backup.
+            Some((Err(err), _)) if !progress.should_stop() => {
+                report.add_failure(&file.get_source(), err.to_string())
+            }
+            _ => {}
+        }
+    }
+
+    let index = SnapshotIndex {
+        entries: entries.into_iter().flatten().collect(),
+    };
+
+    // A cancelled snapshot has no index, the stored files are only in the journal.
+    if !progress.is_cancelled() {
+        index.save(snapshot_path, cipher)?;
+    }
+
+    Ok(index.entries.into_iter().map(|entry| entry.file).collect())
+}
+
+/// Function to read a chunk from the repository, the chunk is decrypted if a cipher is given.
+fn load_chunk(
+    repository_path: &Path,
+    hash: &str,
+    cipher: Option<&Cipher>,
+) -> std::io::Result<Vec<u8>> {
+    let data = std::fs::read(chunk_path(repository_path, hash))?;
+
+    match cipher {
+        Some(cipher) => cipher
+            .open(&data)
+            .map_err(|_| std::io::Error::new(std::io::ErrorKind::InvalidData, "Corrupted chunk")),
+        None => Ok(data),
+    }
+}
+
+/// Reader of a file stored in the repository, the chunks are loaded one at a time while the file is read.
+/// It contains four fields.
+/// * repository_path: the path of the repository on the device.
+/// * chunks: the chunks of the file not loaded yet.
+/// * cipher: the cipher of the chunks, if the encryption is configured.
+/// * current: the chunk being read.
+struct ChunkReader<'a> {
+    repository_path: &'a Path,
+    chunks: std::slice::Iter<'a, String>,
+    cipher: Option<&'a Cipher>,
+    current: Cursor<Vec<u8>>,
+}
+
+impl Read for ChunkReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        loop {
+            let read = self.current.read(buf)?;
+
+            if read > 0 || buf.is_empty() {
+                return Ok(read);
+            }
+
+            match self.chunks.next() {
+                Some(hash) => {
+                    self.current = Cursor::new(load_chunk(self.repository_path, hash, self.cipher)?)
+                }
+                None => return Ok(0),
+            }
+        }
+    }
+}
+
+/// Function to write the content of a stored file, made of the given chunks, to a writer.
+/// The chunks are decrypted if a cipher is given, it returns the BLAKE3 hash of the content.
+fn read_chunks<W: Write>(
+    repository_path: &Path,
+    chunks: &[String],
+    cipher: Option<&Cipher>,
+    writer: &mut W,
+) -> std::io::Result<String> {
+    let mut hasher = blake3::Hasher::new();
+
+    for chunk_hash in chunks {
+        let chunk = load_chunk(repository_path, chunk_hash, cipher)?;
+
+        hasher.update(&chunk);
+        writer.write_all(&chunk)?;
+    }
+
+    Ok(hasher.finalize().to_hex().to_string())
+}
+
+/// Function to load the index of a snapshot stored in the repository, a missing index is an error.
+fn load_index(snapshot_path: &Path, cipher: Option<&Cipher>) -> std::io::Result<SnapshotIndex> {
+    SnapshotIndex::load(snapshot_path, cipher).ok_or(std::io::Error::new(
+        std::io::ErrorKind::NotFound,
+        "Unable to read the index",
+    ))
+}
+
+/// Function to rebuild the files of a snapshot stored in the repository inside the target folder.
+/// The chunks and the index are decrypted if a cipher is given, every file is checked against its hash:
+/// a file whose content doesn't match, because a chunk is corrupted, stops the extraction with an error naming it.
+pub fn extract_snapshot(
+    device_path: &Path,
+    snapshot_name: &str,
+    target: &Path,
+    cipher: Option<&Cipher>,
+) -> std::io::Result<()> {
+    let repository_path = device_path.join(REPOSITORY_DIR_NAME);
+    let index = load_index(&device_path.join(snapshot_name), cipher)?;
+
+    for entry in index.entries {
+        let dst = target.join(entry.file.get_path());
+
+        std::fs::create_dir_all(dst.parent().unwrap())?;
+
+        let mut dst_file = File::create(&dst)?;
+        let hash = read_chunks(&repository_path, &entry.chunks, cipher, &mut dst_file)?;
+
+        let _ = dst_file.set_modified(UNIX_EPOCH + Duration::from_nanos(entry.file.get_modified()));
+
+        if hash != entry.file.get_hash() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("Corrupted file {}", entry.file.get_path()),
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+/// Function to read back every file of a snapshot stored in the repository, it fills the map with the hash of each one.
+/// The files are rebuilt from the chunks on the device without being written anywhere.
+pub fn hash_snapshot(
+    device_path: &Path,
+    snapshot_path: &Path,
+    cipher: Option<&Cipher>,
+    hashes: &mut HashMap<String, std::io::Result<String>>,
+) -> std::io::Result<()> {
+    let repository_path = device_path.join(REPOSITORY_DIR_NAME);
+    let index = load_index(snapshot_path, cipher)?;
+
+    for entry in index.entries {
+        hashes.insert(
+            entry.file.get_path(),
+            read_chunks(
+                &repository_path,
+                &entry.chunks,
+                cipher,
+                &mut std::io::sink(),
+            ),
+        );
+    }
+
+    Ok(())
+}
+
+/// Function to read the files of a snapshot stored in the repository, one at a time.
+/// The callback is called with the path of every file inside the snapshot, its modification time and a reader of its
+/// content, the chunks are loaded while the content is read.
+pub fn read_snapshot<F: FnMut(&str, u64, std::io::Result<&mut dyn Read>)>(
+    device_path: &Path,
+    snapshot_path: &Path,
+    cipher: Option<&Cipher>,
+    mut on_file: F,
+) -> std::io::Result<()> {
+    let repository_path = device_path.join(REPOSITORY_DIR_NAME);
+    let index = load_index(snapshot_path, cipher)?;
+
+    for entry in index.entries.iter() {
+        let mut reader = ChunkReader {
+            repository_path: &repository_path,
+            chunks: entry.chunks.iter(),
+            cipher,
+            current: Cursor::new(Vec::new()),
+        };
+
+        on_file(
+            &entry.file.get_path(),
+            entry.file.get_modified(),
+            Ok(&mut reader),
+        );
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn extract_snapshot_fails_on_a_corrupted_chunk() {
+        let device_path =
+            std::env::temp_dir().join(format!("eb-rs-extract-{}", std::process::id()));
+        let snapshot_name = "backup-2024-01-01_00-00-00";
+        let repository_path = device_path.join(REPOSITORY_DIR_NAME);
+        let content = b"emergency backup".to_vec();
+        let hash = blake3::hash(&content).to_hex().to_string();
+
+        std::fs::create_dir_all(device_path.join(snapshot_name)).unwrap();
+        store_chunk(&repository_path, &hash, &content).unwrap();
+        SnapshotIndex {
+            entries: vec![IndexEntry {
+                file: SnapshotEntry::new(
+                    String::new(),
+                    "notes.txt".to_string(),
+                    content.len() as u64,
+                    0,
+                    hash.clone(),
+                ),
+                chunks: vec![hash.clone()],
+            }],
+        }
+        .save(&device_path.join(snapshot_name), None)
+        .unwrap();
+
+        let intact = extract_snapshot(
+            &device_path,
+            snapshot_name,
+            &device_path.join("intact"),
+            None,
+        );
+
+        let mut chunk = std::fs::read(chunk_path(&repository_path, &hash)).unwrap();
+
+        chunk[0] ^= 0xff;
+        std::fs::write(chunk_path(&repository_path, &hash), chunk).unwrap();
+
+        let corrupted = extract_snapshot(
+            &device_path,
+            snapshot_name,
+            &device_path.join("corrupted"),
+            None,
+        );
+
+        std::fs::remove_dir_all(&device_path).unwrap();
+
+        assert!(intact.is_ok());
+        assert_eq!(
+            corrupted.unwrap_err().kind(),
+            std::io::ErrorKind::InvalidData
+        );
+    }
+}
@@ -0,0 +1,337 @@
+use crate::config::config::BackupFormat;
+use crate::io::archive::read_archive;
+use crate::io::catalog::find_snapshot;
+use crate::io::crypto::Cipher;
+use crate::io::io::{copy_hashed, modified_nanos, read_directory};
+use crate::io::journal::PARTIAL_EXTENSION;
+use crate::io::manifest::{SnapshotEntry, SnapshotManifest};
+use crate::io::report::{BackupError, BackupIssue};
+use crate::io::repository::read_snapshot;
+use crate::io::verify::VerificationReport;
+use byte_unit::{Byte, UnitType};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Read;
+use std::path::{Component, Path, PathBuf};
+use std::time::{Duration, Instant, UNIX_EPOCH};
+
+/// Longest file name most file systems accept, in bytes.
+const MAX_NAME_SIZE: usize = 255;
+
+/// Enum to choose what happens to a restored file whose destination already exists.
+/// * Skip: the existing file is kept and the restored one is skipped.
+/// * Overwrite: the existing file is replaced.
+/// * KeepBoth: the restored file is written next to the existing one, with a numbered name.
+/// * NewerWins: the existing file is replaced only if the restored one has been modified later.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub enum ConflictPolicy {
+    #[default]
+    Skip,
+    Overwrite,
+    KeepBoth,
+    NewerWins,
+}
+
+/// Structure describing what a restore did, contains seven fields.
+/// * snapshot_path: the path of the restored snapshot on the device.
+/// * restored: the number of files written.
+/// * restored_bytes: the bytes written.
+/// * duration: the time the restore took.
+/// * failures: the files that couldn't be restored, with the reason.
+/// * conflicts: the files not restored because their destination already exists, with the reason.
+/// * verification: the result of comparing the restored files with the manifest of the snapshot, if it has one.
+#[derive(Debug, PartialEq, Eq, Clone, Default)]
+pub struct RestoreReport {
+    snapshot_path: PathBuf,
+    restored: usize,
+    restored_bytes: u64,
+    duration: Duration,
+    failures: Vec<BackupIssue>,
+    conflicts: Vec<BackupIssue>,
+    verification: Option<VerificationReport>,
+}
+
+impl RestoreReport {
+    /// Function to check if every selected file has been restored identical to its source, or left in place on purpose.
+    pub fn is_successful(&self) -> bool {
+        self.failures.is_empty()
+            && self
+                .verification
+                .as_ref()
+                .is_none_or(|verification| verification.is_passed())
+    }
+}
+
+impl std::fmt::Display for RestoreReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "Restore completed in {:.1}s: {}",
+            self.duration.as_secs_f64(),
+            self.snapshot_path.display()
+        )?;
+        writeln!(
+            f,
+            "{} files, {:.1} restored",
+            self.restored,
+            Byte::from(self.restored_bytes).get_appropriate_unit(UnitType::Decimal)
+        )?;
+
+        for failure in self.failures.iter() {
+            writeln!(f, "Failed {}", failure)?;
+        }
+
+        for conflict in self.conflicts.iter() {
+            writeln!(f, "Kept {}", conflict)?;
+        }
+
+        match self.verification.as_ref() {
+            Some(verification) => write!(f, "{}", verification),
+            None => write!(f, "Verification skipped, the snapshot has no manifest"),
+        }
+    }
+}
+
+/// Function to check if a file of the snapshot has been selected, by its path inside the snapshot or by its source.
+/// A selected folder selects every file inside it, an empty selection selects the whole snapshot.
+fn is_selected(path: &str, entry: Option<&SnapshotEntry>, selection: &[String]) -> bool {
+    selection.is_empty()
+        || selection.iter().any(|selected| {
+            Path::new(path).starts_with(selected)
+                || entry.is_some_and(|entry| Path::new(&entry.get_source()).starts_with(selected))
+        })
+}
+
+/// Function to check the path of a file read from the snapshot before writing it, a tampered device could otherwise
+/// write outside the destination. A path inside the snapshot must be made only of names, without `..` or a root, and an
+/// original path from the manifest must be absolute and made only of names after the root.
+fn is_safe_path(path: &Path, absolute: bool) -> bool {
+    let mut components = path.components().peekable();
+
+    if absolute && components.next() != Some(Component::RootDir) {
+        return false;
+    }
+
+    components.peek().is_some()
+        && components.all(|component| matches!(component, Component::Normal(_)))
+}
+
+/// Function to get a free path next to an existing file, numbering the restored copy: `notes (restored).txt`,
+/// `notes (restored 2).txt` and so on.
+fn free_path(destination: &Path) -> PathBuf {
+    let stem = destination
+        .file_stem()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .to_string();
+    let extension = destination
+        .extension()
+        .map(|extension| format!(".{}", extension.to_string_lossy()))
+        .unwrap_or_default();
+
+    (1..)
+        .map(|n| {
+            let suffix = if n == 1 {
+                "restored".to_string()
+            } else {
+                format!("restored {}", n)
+            };
+
+            destination.with_file_name(format!("{} ({}){}", stem, suffix, extension))
+        })
+        .find(|path| !path.exists())
+        .unwrap()
+}
+
+/// Function to write a file of the snapshot at its destination, following the conflict policy if the destination exists.
+/// The content is written next to the destination and renamed over it once complete, so an interrupted restore never
+/// leaves a truncated file in place of an existing one.
+/// It returns the path written and the BLAKE3 hash of the content, or None if the existing file has been kept.
+fn restore_file(
+    reader: &mut dyn Read,
+    destination: &Path,
+    modified: u64,
+    conflict_policy: ConflictPolicy,
+) -> std::io::Result<Option<(PathBuf, String)>> {
+    let destination = match std::fs::metadata(destination) {
+        Err(_) => destination.to_path_buf(),
+        Ok(metadata) => match conflict_policy {
+            ConflictPolicy::Skip => return Ok(None),
+            ConflictPolicy::NewerWins if modified_nanos(&metadata) >= modified => return Ok(None),
+            ConflictPolicy::Overwrite | ConflictPolicy::NewerWins => destination.to_path_buf(),
+            ConflictPolicy::KeepBoth => free_path(destination),
+        },
+    };
+
+    if let Some(parent) = destination.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+
+    let mut partial_name = destination.file_name().unwrap_or_default().to_os_string();
+
+    // A name too long to take the extension is replaced by its hash, a file system allows names up to 255 bytes.
+    if partial_name.len() + PARTIAL_EXTENSION.len() >= MAX_NAME_SIZE {
+        partial_name = blake3::hash(partial_name.as_encoded_bytes())
+            .to_hex()
+            .to_string()
+            .into();
+    }
+
+    partial_name.push(format!(".{}", PARTIAL_EXTENSION));
+
+    let partial_path = destination.with_file_name(partial_name);
+    let written = File::create(&partial_path).and_then(|mut dst_file| {
+        let hash = copy_hashed(reader, &mut dst_file)?;
+
+        let _ = dst_file.set_modified(UNIX_EPOCH + Duration::from_nanos(modified));
+        dst_file.sync_all()?;
+
+        Ok(hash)
+    });
+
+    match written.and_then(|hash| std::fs::rename(&partial_path, &destination).map(|_| hash)) {
+        Ok(hash) => Ok(Some((destination, hash))),
+        Err(err) => {
+            let _ = std::fs::remove_file(&partial_path);
+
+            Err(err)
+        }
+    }
+}
+
+/// Function to restore the files of a snapshot, all of them or the selected files and folders.
+/// The files go back to their original paths, read from the manifest of the snapshot, or inside the target folder with
+/// their path in the snapshot. An incomplete snapshot can be restored too, from its partial folder.
+/// Every restored file is hashed while it's written and compared with the manifest, if the snapshot has one,
+/// the selected files missing from the snapshot are reported too. Files whose path would lead outside their destination
+/// are not written and are reported as failures.
+pub fn restore_snapshot(
+    device_path: &Path,
+    snapshot_name: &str,
+    selection: &[String],
+    target: Option<&Path>,
+    conflict_policy: ConflictPolicy,
+    cipher: Option<&Cipher>,
+) -> Result<RestoreReport, BackupError> {
+    let start = Instant::now();
+    let snapshot = find_snapshot(device_path, snapshot_name)
+        .ok_or(BackupError::SnapshotNotFound(snapshot_name.to_string()))?;
+    let snapshot_path = snapshot.get_path(device_path);
+
+    let manifest = SnapshotManifest::load(&snapshot_path, cipher);
+
+    if manifest.is_none() && target.is_none() {
+        return Err(BackupError::TargetNeeded);
+    }
+
+    let mut entries = manifest
+        .as_ref()
+        .map(|manifest| {
+            manifest
+                .get_entries()
+                .into_iter()
+                .map(|entry| (entry.get_path(), entry))
+                .collect::<HashMap<String, SnapshotEntry>>()
+        })
+        .unwrap_or_default();
+    let mut report = RestoreReport {
+        snapshot_path: snapshot_path.clone(),
+        verification: manifest.as_ref().map(|_| VerificationReport::default()),
+        ..Default::default()
+    };
+
+    let on_file = |path: &str, modified: u64, reader: std::io::Result<&mut dyn Read>| {
+        let entry = entries.remove(path);
+
+        if !is_selected(path, entry.as_ref(), selection) {
+            return;
+        }
+
+        let destination = match (target, entry.as_ref()) {
+            (Some(_), _) if !is_safe_path(Path::new(path), false) => {
+                report.failures.push(BackupIssue::new(
+                    Path::new(path),
+                    "the path leads outside the target folder".to_string(),
+                ));
+
+                return;
+            }
+            (Some(target), _) => target.join(path),
+            (None, Some(entry)) if !is_safe_path(Path::new(&entry.get_source()), true) => {
+                report.failures.push(BackupIssue::new(
+                    Path::new(path),
+                    format!("the original path {} is not valid", entry.get_source()),
+                ));
+
+                return;
+            }
+            (None, Some(entry)) => PathBuf::from(entry.get_source()),
+            (None, None) => {
+                report.failures.push(BackupIssue::new(
+                    Path::new(path),
+                    "not in the manifest, its original path is unknown".to_string(),
+                ));
+
+                return;
+            }
+        };
+        let restored =
+            reader.and_then(|reader| restore_file(reader, &destination, modified, conflict_policy));
+
+        match restored {
+            Ok(Some((written, hash))) => {
+                report.restored += 1;
+                report.restored_bytes += std::fs::metadata(&written)
+                    .map(|metadata| metadata.len())
+                    .unwrap_or(0);
+
+                if let (Some(verification), Some(entry)) =
+                    (report.verification.as_mut(), entry.as_ref())
+                {
+                    verification.check(path.to_string(), &entry.get_hash(), Some(Ok(hash)));
+                }
+            }
+            Ok(None) => report.conflicts.push(BackupIssue::new(
+                &destination,
+                match conflict_policy {
+                    ConflictPolicy::NewerWins => "the existing file is newer",
+                    _ => "the file already exists",
+                }
+                .to_string(),
+            )),
+            Err(err) => report
+                .failures
+                .push(BackupIssue::new(&destination, err.to_string())),
+        }
+    };
+
+    let read = match snapshot.get_backup_format() {
+        BackupFormat::Directory => read_directory(&snapshot_path, cipher, on_file),
+        BackupFormat::Repository => read_snapshot(device_path, &snapshot_path, cipher, on_file),
+        BackupFormat::Archive => read_archive(&snapshot_path, cipher, on_file),
+    };
+
+    if let Err(err) = read {
+        report
+            .failures
+            .push(BackupIssue::new(&snapshot_path, err.to_string()));
+    }
+
+    // The entries left have not been found in the snapshot.
+    if let Some(verification) = report.verification.as_mut() {
+        let mut missing = entries
+            .into_values()
+            .filter(|entry| is_selected(&entry.get_path(), Some(entry), selection))
+            .collect::<Vec<SnapshotEntry>>();
+
+        missing.sort_by_key(|entry| entry.get_path());
+
+        for entry in missing {
+            verification.check(entry.get_path(), &entry.get_hash(), None);
+        }
+    }
+
+    report.duration = start.elapsed();
+
+    Ok(report)
+}
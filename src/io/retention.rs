@@ -0,0 +1,157 @@
+use crate::config::config::Retention;
+use crate::io::catalog::{list_snapshots, SnapshotInfo};
+use crate::io::crypto::Cipher;
+use crate::io::io::get_available_space;
+use crate::io::report::BackupReport;
+use crate::io::repository::{remove_chunk, snapshot_chunks};
+use chrono::{Datelike, NaiveDateTime};
+use std::collections::{HashMap, HashSet};
+use std::hash::Hash;
+use std::path::Path;
+
+/// Function to list the complete snapshots of the device, sorted from the newest.
+/// Incomplete snapshots are still being written or have been interrupted, they are never listed.
+/// Legacy snapshots, written before the completion record existed, can't be trusted either, they are never removed.
+fn complete_snapshots(device_path: &Path) -> Vec<SnapshotInfo> {
+    list_snapshots(device_path)
+        .into_iter()
+        .filter(|snapshot| snapshot.is_complete())
+        .collect()
+}
+
+/// Function to keep the most recent snapshot of each period, for the given number of most recent periods.
+/// The period of a snapshot is identified by the key computed from its time.
+fn keep_periods<K: Eq + Hash, F: Fn(&NaiveDateTime) -> K>(
+    snapshots: &[SnapshotInfo],
+    count: usize,
+    period: F,
+    kept: &mut HashSet<String>,
+) {
+    let mut periods = HashSet::new();
+
+    for snapshot in snapshots {
+        if periods.len() == count {
+            break;
+        }
+
+        if periods.insert(period(&snapshot.get_time())) {
+            kept.insert(snapshot.get_name());
+        }
+    }
+}
+
+/// Function to select the snapshots kept by the retention policy, the newest snapshot is always kept.
+fn retained_snapshots(snapshots: &[SnapshotInfo], retention: Retention) -> HashSet<String> {
+    let mut kept = HashSet::new();
+
+    for snapshot in snapshots.iter().take(retention.get_keep_last().max(1)) {
+        kept.insert(snapshot.get_name());
+    }
+
+    keep_periods(
+        snapshots,
+        retention.get_keep_daily(),
+        |time| time.date(),
+        &mut kept,
+    );
+    keep_periods(
+        snapshots,
+        retention.get_keep_weekly(),
+        |time| time.iso_week(),
+        &mut kept,
+    );
+    keep_periods(
+        snapshots,
+        retention.get_keep_monthly(),
+        |time| (time.year(), time.month()),
+        &mut kept,
+    );
+
+    kept
+}
+
+/// Function to count how many times every chunk of the repository is used by the snapshots of the device.
+/// If an index can't be read it returns None, since a chunk can't be known to be unused anymore.
+fn count_chunk_references(
+    device_path: &Path,
+    cipher: Option<&Cipher>,
+) -> Option<HashMap<String, usize>> {
+    let mut references = HashMap::new();
+
+    for entry in std::fs::read_dir(device_path).ok()?.flatten() {
+        if !entry.file_name().to_string_lossy().starts_with("backup-") {
+            continue;
+        }
+
+        for chunk in snapshot_chunks(&entry.path(), cipher).ok()? {
+            *references.entry(chunk).or_insert(0) += 1;
+        }
+    }
+
+    Some(references)
+}
+
+/// Function to remove the old snapshots not kept by the retention policy, until the device has the required space.
+/// The oldest snapshots are removed first, incomplete snapshots and the newest complete one are never removed.
+/// The chunks of the repository no longer used by any snapshot are removed too.
+/// The removed snapshots, and the ones that can't be removed, are added to the report.
+pub fn prune_snapshots(
+    device_path: &Path,
+    retention: Retention,
+    required_space: u64,
+    cipher: Option<&Cipher>,
+    report: &mut BackupReport,
+) {
+    let mut available_space = match get_available_space(device_path) {
+        Some(available_space) => available_space,
+        None => return,
+    };
+
+    if !retention.is_enabled() || available_space >= required_space {
+        return;
+    }
+
+    let snapshots = complete_snapshots(device_path);
+    let kept = retained_snapshots(&snapshots, retention);
+    let mut chunk_references = count_chunk_references(device_path, cipher);
+
+    for snapshot in snapshots
+        .iter()
+        .rev()
+        .filter(|snapshot| !kept.contains(&snapshot.get_name()))
+    {
+        if available_space >= required_space {
+            break;
+        }
+
+        let snapshot_path = snapshot.get_path(device_path);
+        let chunks = snapshot_chunks(&snapshot_path, cipher).unwrap_or_default();
+
+        if let Err(err) = std::fs::remove_dir_all(&snapshot_path) {
+            report.add_failure(&snapshot_path, err.to_string());
+            continue;
+        }
+
+        if let Some(chunk_references) = chunk_references.as_mut() {
+            for chunk in chunks {
+                let references = chunk_references.entry(chunk.clone()).or_insert(1);
+
+                *references -= 1;
+
+                if *references == 0 {
+                    chunk_references.remove(&chunk);
+
+                    if let Err(err) = remove_chunk(device_path, &chunk) {
+                        report.add_failure(
+                            &snapshot_path,
+                            format!("unable to remove the chunk {}, {}", chunk, err),
+                        );
+                    }
+                }
+            }
+        }
+
+        report.add_removed_snapshot(snapshot.get_name());
+        available_space = get_available_space(device_path).unwrap_or(available_space);
+    }
+}
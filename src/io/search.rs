@@ -0,0 +1,211 @@
+use crate::io::catalog::{list_snapshots, snapshot_entries, SnapshotStatus};
+use crate::io::crypto::Cipher;
+use crate::io::report::{BackupError, BackupIssue};
+use byte_unit::{Byte, UnitType};
+use chrono::{DateTime, Local};
+use globset::{GlobBuilder, GlobMatcher};
+use serde::Serialize;
+use std::path::Path;
+
+/// Number of characters of the hash shown for every version, enough to tell the versions of a file apart.
+const SHORT_HASH_LENGTH: usize = 16;
+
+/// Enum describing how the files are matched by a search.
+/// * Glob: a glob pattern, matched against the file name, or against the whole path if the pattern holds a `/`.
+/// * Substring: a text found anywhere in the path inside the snapshot or in the source, ignoring the case.
+enum SearchPattern {
+    Glob(GlobMatcher, bool),
+    Substring(String),
+}
+
+impl SearchPattern {
+    /// Function to build the matcher of a pattern, it's a glob if it holds any of `*`, `?`, `[` or `{`.
+    fn new(pattern: &str) -> Result<SearchPattern, BackupError> {
+        if !pattern.contains(['*', '?', '[', '{']) {
+            return Ok(SearchPattern::Substring(pattern.to_lowercase()));
+        }
+
+        // The file systems of macOS ignore the case of the names by default, so does the search.
+        GlobBuilder::new(pattern)
+            .literal_separator(true)
+            .case_insensitive(true)
+            .build()
+            .map(|glob| SearchPattern::Glob(glob.compile_matcher(), pattern.contains('/')))
+            .map_err(|err| BackupError::InvalidPattern(err.to_string()))
+    }
+
+    /// Function to check if a file matches, by its path inside the snapshot or by its source, if known.
+    fn is_match(&self, path: &str, source: &str) -> bool {
+        match self {
+            SearchPattern::Glob(matcher, true) => {
+                matcher.is_match(path) || (!source.is_empty() && matcher.is_match(source))
+            }
+            SearchPattern::Glob(matcher, false) => Path::new(path)
+                .file_name()
+                .is_some_and(|file_name| matcher.is_match(file_name)),
+            SearchPattern::Substring(text) => {
+                path.to_lowercase().contains(text) || source.to_lowercase().contains(text)
+            }
+        }
+    }
+}
+
+/// Structure describing a version of a file found in a snapshot, contains six fields.
+/// * snapshot: the name of the snapshot holding the version.
+/// * path: the path of the file inside the snapshot, the one to give to the restore.
+/// * source: the absolute path of the source file, empty for legacy snapshots.
+/// * size: the size of the file in bytes.
+/// * modified: the modification time of the file, in nanoseconds since the unix epoch.
+/// * hash: the BLAKE3 hash of the content of the file.
+#[derive(Debug, PartialEq, Eq, Clone, Serialize)]
+pub struct FileVersion {
+    snapshot: String,
+    path: String,
+    source: String,
+    size: u64,
+    modified: u64,
+    hash: String,
+}
+
+/// Structure with the result of a search across the snapshots of a device, contains three fields.
+/// * pattern: the pattern searched.
+/// * versions: the versions of the matching files, grouped by path and sorted from the oldest snapshot.
+/// * unreadable: the snapshots whose files can't be listed, with the reason.
+#[derive(Debug, PartialEq, Eq, Clone, Serialize)]
+pub struct SearchReport {
+    pattern: String,
+    versions: Vec<FileVersion>,
+    unreadable: Vec<BackupIssue>,
+}
+
+impl SearchReport {
+    /// Function to write the versions found as json, for other tools, with the snapshots that couldn't be searched.
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).unwrap()
+    }
+}
+
+impl std::fmt::Display for SearchReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut paths = self
+            .versions
+            .iter()
+            .map(|version| version.path.as_str())
+            .collect::<Vec<&str>>();
+
+        paths.dedup();
+
+        write!(
+            f,
+            "{} versions of {} files matching \"{}\"",
+            self.versions.len(),
+            paths.len(),
+            self.pattern
+        )?;
+
+        let mut previous: Option<&FileVersion> = None;
+
+        for version in self.versions.iter() {
+            let same_path = previous.is_some_and(|previous| previous.path == version.path);
+
+            if !same_path {
+                write!(f, "\n\n{}", version.path)?;
+
+                // Legacy snapshots don't know the source, the other versions tell it.
+                if let Some(source) = self
+                    .versions
+                    .iter()
+                    .filter(|other| other.path == version.path && !other.source.is_empty())
+                    .map(|other| &other.source)
+                    .next()
+                {
+                    write!(f, "  ({})", source)?;
+                }
+            }
+
+            let modified = DateTime::from_timestamp_nanos(version.modified as i64)
+                .with_timezone(&Local)
+                .format("%Y-%m-%d %H:%M:%S");
+
+            write!(
+                f,
+                "\n  {}  modified {}  {:>10}  {}",
+                version.snapshot,
+                modified,
+                format!(
+                    "{:.1}",
+                    Byte::from(version.size).get_appropriate_unit(UnitType::Decimal)
+                ),
+                &version.hash[..SHORT_HASH_LENGTH.min(version.hash.len())]
+            )?;
+
+            // The same version is kept by every following snapshot until the file changes.
+            if same_path && previous.is_some_and(|previous| previous.hash == version.hash) {
+                write!(f, "  unchanged")?;
+            }
+
+            previous = Some(version);
+        }
+
+        for unreadable in self.unreadable.iter() {
+            write!(f, "\nUnreadable {}", unreadable)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Function to search the files matching a pattern across the snapshots of a device, to find the versions of a file.
+/// Incomplete snapshots are left out, since their files are not listed until they are completed. Legacy snapshots are
+/// read whole to hash their files, a search over many of them takes a while.
+pub fn search_snapshots(
+    device_path: &Path,
+    pattern: &str,
+    cipher: Option<&Cipher>,
+) -> Result<SearchReport, BackupError> {
+    let search_pattern = SearchPattern::new(pattern)?;
+    let mut versions = Vec::new();
+    let mut unreadable = Vec::new();
+    let mut snapshots = list_snapshots(device_path);
+
+    snapshots.reverse();
+
+    for snapshot in snapshots
+        .iter()
+        .filter(|snapshot| snapshot.get_status() != SnapshotStatus::Incomplete)
+    {
+        let entries = match snapshot_entries(device_path, snapshot, cipher) {
+            Ok(entries) => entries,
+            Err(err) => {
+                unreadable.push(BackupIssue::new(
+                    &snapshot.get_path(device_path),
+                    err.to_string(),
+                ));
+                continue;
+            }
+        };
+
+        versions.extend(
+            entries
+                .into_iter()
+                .filter(|entry| search_pattern.is_match(&entry.get_path(), &entry.get_source()))
+                .map(|entry| FileVersion {
+                    snapshot: snapshot.get_name(),
+                    path: entry.get_path(),
+                    source: entry.get_source(),
+                    size: entry.get_size(),
+                    modified: entry.get_modified(),
+                    hash: entry.get_hash(),
+                }),
+        );
+    }
+
+    // The sort is stable, so the versions of a file stay sorted from the oldest snapshot.
+    versions.sort_by(|a, b| a.path.cmp(&b.path));
+
+    Ok(SearchReport {
+        pattern: pattern.to_string(),
+        versions,
+        unreadable,
+    })
+}
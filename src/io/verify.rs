@@ -0,0 +1,154 @@
+use crate::config::config::BackupFormat;
+use crate::io::archive::hash_archive;
+use crate::io::crypto::Cipher;
+use crate::io::io::hash_reader;
+use crate::io::manifest::{SnapshotManifest, SNAPSHOT_MANIFEST_FILE_NAME};
+use crate::io::repository::hash_snapshot;
+use std::collections::HashMap;
+use std::fs::File;
+use std::path::Path;
+
+/// Structure describing a file that didn't pass the verification, contains two fields.
+/// * path: the path of the file inside the snapshot.
+/// * reason: why the file didn't pass the verification.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct VerificationFailure {
+    path: String,
+    reason: String,
+}
+
+impl VerificationFailure {
+    pub fn new(path: String, reason: String) -> Self {
+        VerificationFailure { path, reason }
+    }
+}
+
+impl std::fmt::Display for VerificationFailure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.path, self.reason)
+    }
+}
+
+/// Structure with the result of the verification of a snapshot, contains two fields.
+/// * checked: the number of files read back from the device.
+/// * failures: the files whose copy is missing, unreadable or different from the source.
+#[derive(Debug, PartialEq, Eq, Clone, Default)]
+pub struct VerificationReport {
+    checked: usize,
+    failures: Vec<VerificationFailure>,
+}
+
+impl VerificationReport {
+    /// Function to check if every file of the snapshot has been read back identical to its source.
+    pub fn is_passed(&self) -> bool {
+        self.failures.is_empty()
+    }
+
+    /// Function to record the result of reading back a file, comparing its hash with the one of the source.
+    pub fn check(&mut self, path: String, expected: &str, actual: Option<std::io::Result<String>>) {
+        self.checked += 1;
+
+        let reason = match actual {
+            Some(Ok(hash)) if hash == expected => return,
+            Some(Ok(hash)) => format!("hash mismatch, expected {} found {}", expected, hash),
+            Some(Err(err)) => format!("unreadable, {}", err),
+            None => "missing".to_string(),
+        };
+
+        self.failures.push(VerificationFailure::new(path, reason));
+    }
+}
+
+impl std::fmt::Display for VerificationReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.is_passed() {
+            return write!(f, "Verification passed, {} files checked", self.checked);
+        }
+
+        write!(
+            f,
+            "Verification failed for {} of {} files",
+            self.failures.len(),
+            self.checked
+        )?;
+
+        for failure in self.failures.iter() {
+            write!(f, "\n{}", failure)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Function to verify a snapshot just written on the device, every file is read back and its hash compared with the source one.
+/// Files are read the same way a restore would: decrypted, rebuilt from the chunks or extracted from the archive.
+/// The manifest saved in the snapshot is read back too, so the hashes are available to later checks.
+pub fn verify_snapshot(
+    device_path: &Path,
+    snapshot_path: &Path,
+    backup_format: BackupFormat,
+    snapshot_manifest: &SnapshotManifest,
+    cipher: Option<&Cipher>,
+) -> VerificationReport {
+    let mut report = VerificationReport::default();
+    let mut hashes = HashMap::new();
+    let mut read_error = None;
+
+    if SnapshotManifest::load(snapshot_path, cipher).as_ref() != Some(snapshot_manifest) {
+        report.failures.push(VerificationFailure::new(
+            SNAPSHOT_MANIFEST_FILE_NAME.to_string(),
+            "unreadable or different from the written one".to_string(),
+        ));
+    }
+
+    match backup_format {
+        BackupFormat::Directory => {
+            for entry in snapshot_manifest.get_entries() {
+                hashes.insert(
+                    entry.get_path(),
+                    hash_directory_file(snapshot_path, &entry.get_path(), cipher),
+                );
+            }
+        }
+        BackupFormat::Repository => {
+            if let Err(err) = hash_snapshot(device_path, snapshot_path, cipher, &mut hashes) {
+                read_error = Some(err);
+            }
+        }
+        BackupFormat::Archive => {
+            if let Err(err) = hash_archive(snapshot_path, cipher, &mut hashes) {
+                read_error = Some(err);
+            }
+        }
+    }
+
+    for entry in snapshot_manifest.get_entries() {
+        let actual = match (hashes.remove(&entry.get_path()), read_error.as_ref()) {
+            (None, Some(err)) => Some(Err(std::io::Error::new(err.kind(), err.to_string()))),
+            (actual, _) => actual,
+        };
+
+        report.check(entry.get_path(), &entry.get_hash(), actual);
+    }
+
+    report
+}
+
+/// Function to read back a file of a snapshot written as a directory tree, it returns the hash of its content.
+fn hash_directory_file(
+    snapshot_path: &Path,
+    path: &str,
+    cipher: Option<&Cipher>,
+) -> std::io::Result<String> {
+    match cipher {
+        Some(cipher) => {
+            let relative_path = cipher.encrypt_path(Path::new(path)).map_err(|_| {
+                std::io::Error::new(std::io::ErrorKind::InvalidInput, "Invalid file name")
+            })?;
+            let file = File::open(snapshot_path.join(relative_path))?;
+
+            hash_reader(&mut cipher.decrypt_reader(file)?)
+        }
+        None => hash_reader(&mut File::open(snapshot_path.join(path))?),
+    }
+}
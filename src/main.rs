@@ -1,13 +1,18 @@
 use std::env;
 
+use cli::cli::run_command;
 use config::config::get_configuration;
 use gui::config_gui::*;
 use gui::gui::ExitStatus;
 use gui::utils_gui::*;
+use io::cancel::watch_cancellation;
 use io::io::execute_copy;
+use io::progress::{log_progress, Progress};
 use job::job::*;
+use log::{error, info, warn};
 use pattern_recognition::pattern_recognition::{PatternRecognition, RectanglePattern};
 
+mod cli;
 mod config;
 mod gui;
 mod io;
@@ -16,6 +21,12 @@ mod pattern_recognition;
 mod utils;
 
 fn main() {
+    let args = env::args().skip(1).collect::<Vec<String>>();
+
+    if run_command(&args) {
+        return;
+    }
+
     let launch_job = env::var("LAUNCH_JOB").is_ok_and(|var| var == "TRUE");
     let inside_job = env::var("INSIDE_JOB").is_ok_and(|var| var == "TRUE");
     let configuration = get_configuration();
@@ -30,10 +41,32 @@ fn main() {
                 let exit_status = start_warning_gui();
 
                 if exit_status == ExitStatus::COMPLETED {
-                    let device_name = configuration.clone().unwrap().get_device_name();
-                    let path_names = configuration.clone().unwrap().get_path_names();
+                    let progress = Progress::new();
+                    let updates = progress.subscribe();
+
+                    std::thread::spawn(move || log_progress(updates));
+
+                    let _cancellation = watch_cancellation(&progress.get_cancellation_token());
+
+                    // The window must run on the main thread, so the copy runs in its own.
+                    let copy = {
+                        let configuration = configuration.clone().unwrap();
+                        let progress = progress.clone();
+
+                        std::thread::spawn(move || execute_copy(&configuration, &progress))
+                    };
+
+                    start_progress_gui(&progress);
+
+                    let result = copy.join().unwrap();
+
+                    match &result {
+                        Ok(report) if report.is_successful() => info!("{}", report),
+                        Ok(report) => warn!("{}", report),
+                        Err(err) => error!("Backup failed: {}", err),
+                    }
 
-                    execute_copy(device_name, path_names);
+                    start_summary_gui(&result);
                 }
 
                 kill_job(true);